@@ -0,0 +1,268 @@
+//! Signing for S3 streaming uploads (`STREAMING-AWS4-HMAC-SHA256-PAYLOAD`).
+//!
+//! Instead of hashing the whole body up front, the body is sent with `Content-Encoding:
+//! aws-chunked` and split into chunks that each carry a signature.  The first chunk signature is
+//! chained from the "seed" signature of the request headers, and every chunk after that is chained
+//! from the previous one, ending with an empty chunk.  This lets us sign large uploads without
+//! buffering them in memory.
+//!
+//! See https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-streaming.html
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use hmac_sha256::HMAC;
+
+use hex;
+
+use rusoto_credential::AwsCredentials;
+use hyper::{Body, Method, Request};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH};
+
+use super::{AwsUTCDateStrings, Payload, SignedHeadersPolicy, SigningError, XAMZCONTENTSHA256};
+use super::endpoints::EndpointResolver;
+
+pub const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+const XAMZDECODEDCONTENTLENGTH: &str = "x-amz-decoded-content-length";
+
+/// S3 requires every chunk except the last one to be at least 8KB.  64KB is what the AWS examples
+/// use.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the hex encoded signature in each chunk header.
+const SIGNATURE_LENGTH: u64 = 64;
+
+/// Returns the length of the body if this request should be sent as a streaming upload instead of
+/// being buffered and signed all at once.
+///
+/// For now this is only done for S3 `PUT`s that we know the length of (S3 needs
+/// `x-amz-decoded-content-length`), and that are big enough to be worth it.  Multi-Region Access
+/// Points are left out, since they need SigV4a and the chunks are only signed with SigV4.
+pub fn streaming_content_length(req: &Request<Body>, resolver: &EndpointResolver) -> Option<u64> {
+    if req.method() != Method::PUT {
+        return None;
+    }
+    if req.uri().host().map_or(false, super::sigv4a::is_multi_region_host) {
+        return None;
+    }
+    if super::service_name(req, resolver).as_ref().map(String::as_str) != Some("s3") {
+        return None;
+    }
+    let content_length = req.headers().get(CONTENT_LENGTH)?.to_str().ok()?.parse::<u64>().ok()?;
+    if content_length > DEFAULT_CHUNK_SIZE as u64 {
+        Some(content_length)
+    } else {
+        None
+    }
+}
+
+/// Returns the `Content-Length` of a body of `decoded_length` bytes once it is split into chunks of
+/// `chunk_size` and each chunk is framed with its signature.
+pub fn encoded_content_length(decoded_length: u64, chunk_size: usize) -> u64 {
+    let chunk_size = chunk_size as u64;
+    let full_chunks = decoded_length / chunk_size;
+    let remainder = decoded_length % chunk_size;
+    let mut length = full_chunks * framed_chunk_length(chunk_size);
+    if remainder > 0 {
+        length += framed_chunk_length(remainder);
+    }
+    length + framed_chunk_length(0)
+}
+
+/// `<hex size>;chunk-signature=<signature>\r\n<data>\r\n`
+fn framed_chunk_length(size: u64) -> u64 {
+    format!("{:x}", size).len() as u64 + ";chunk-signature=".len() as u64 + SIGNATURE_LENGTH
+        + 2 + size + 2
+}
+
+/// Rewrites the headers of `req` for a streaming upload of `decoded_content_length` bytes, and
+/// adds the signature headers.  The returned encoder must be used to frame the body.
+pub fn sign_streaming_request(
+    aws_utc_datestrings: AwsUTCDateStrings,
+    credentials: AwsCredentials,
+    req: &mut Request<Body>,
+    decoded_content_length: u64,
+    policy: &SignedHeadersPolicy,
+    resolver: &EndpointResolver) -> Result<ChunkedEncoder, SigningError> {
+
+    let content_encoding = match req.headers().get(CONTENT_ENCODING) {
+        Some(existing) => format!("aws-chunked,{}", existing.to_str().unwrap_or("")),
+        None => String::from("aws-chunked"),
+    };
+    let headers = req.headers_mut();
+    headers.insert(CONTENT_ENCODING,
+        super::header_value(CONTENT_ENCODING.as_str(), &content_encoding)?);
+    headers.insert(XAMZDECODEDCONTENTLENGTH, HeaderValue::from(decoded_content_length));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(
+        encoded_content_length(decoded_content_length, DEFAULT_CHUNK_SIZE)));
+
+    let signature = super::sign(
+        aws_utc_datestrings.clone(),
+        &credentials,
+        req,
+        Payload::StreamingChunked,
+        policy,
+        resolver,
+        &["content-encoding", XAMZCONTENTSHA256, XAMZDECODEDCONTENTLENGTH])?;
+    super::add_aws_signature_headers(req, signature.headers)?;
+    let signer = ChunkSigner::new(
+        aws_utc_datestrings,
+        credentials.aws_secret_access_key(),
+        &signature.region,
+        &signature.service,
+        signature.signature);
+    Ok(ChunkedEncoder::new(signer, DEFAULT_CHUNK_SIZE))
+}
+
+/// Calculates the chained signature of each chunk.
+pub struct ChunkSigner {
+    signing_key: [u8; 32],
+    amzdate: String,
+    credential_scope: String,
+    previous_signature: String,
+}
+
+impl ChunkSigner {
+    pub fn new(
+        aws_utc_datestrings: AwsUTCDateStrings,
+        secret_key: &str,
+        region: &str,
+        service: &str,
+        seed_signature: String) -> Self {
+        ChunkSigner {
+            signing_key: super::derive_signing_key(&aws_utc_datestrings.datestamp, secret_key,
+                region, service),
+            credential_scope: format!("{}/{}/{}/aws4_request", aws_utc_datestrings.datestamp,
+                region, service),
+            amzdate: aws_utc_datestrings.amzdate,
+            previous_signature: seed_signature,
+        }
+    }
+
+    /// Returns the signature of the next chunk.
+    pub fn sign_chunk(&mut self, data: &[u8]) -> String {
+        let string_to_sign = format!("AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            self.amzdate, self.credential_scope, self.previous_signature, sha256_hex(&[]),
+            sha256_hex(data));
+        let signature = hex::encode(HMAC::mac(string_to_sign.as_bytes(), &self.signing_key)
+            .to_vec());
+        self.previous_signature = signature.clone();
+        signature
+    }
+
+    /// Returns the next chunk, framed with its signature.
+    pub fn encode_chunk(&mut self, data: &[u8]) -> Vec<u8> {
+        let signature = self.sign_chunk(data);
+        let mut chunk = format!("{:x};chunk-signature={}\r\n", data.len(), signature).into_bytes();
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(b"\r\n");
+        chunk
+    }
+}
+
+/// Splits a body into fixed size signed chunks as it streams through, so the encoded length
+/// matches `encoded_content_length`.
+pub struct ChunkedEncoder {
+    signer: ChunkSigner,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl ChunkedEncoder {
+    pub fn new(signer: ChunkSigner, chunk_size: usize) -> Self {
+        ChunkedEncoder { signer, chunk_size, buffer: Vec::with_capacity(chunk_size) }
+    }
+
+    /// Takes the next piece of the body, and returns all the chunks that are complete so far.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(data);
+        let mut encoded = Vec::new();
+        while self.buffer.len() >= self.chunk_size {
+            let rest = self.buffer.split_off(self.chunk_size);
+            encoded.extend(self.signer.encode_chunk(&self.buffer));
+            self.buffer = rest;
+        }
+        encoded
+    }
+
+    /// Returns whatever is left of the body, followed by the final empty chunk.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        if !self.buffer.is_empty() {
+            encoded.extend(self.signer.encode_chunk(&self.buffer));
+            self.buffer.clear();
+        }
+        encoded.extend(self.signer.encode_chunk(&[]));
+        encoded
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+#[cfg(test)]
+mod tests {
+
+    /// The example from https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-streaming.html
+    fn example_signer() -> super::ChunkSigner {
+        super::ChunkSigner::new(
+            super::AwsUTCDateStrings{
+                amzdate: String::from("20130524T000000Z"),
+                datestamp: String::from("20130524")
+            },
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "s3",
+            String::from("4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9"))
+    }
+
+    #[test]
+    fn test_encoded_content_length() {
+        assert_eq!(super::encoded_content_length(66560, 65536), 66824);
+        assert_eq!(super::encoded_content_length(0, 65536), 86);
+    }
+
+    #[test]
+    fn test_streaming_content_length() {
+        let resolver = super::EndpointResolver::default();
+        let put = |uri: &str| hyper::Request::put(uri)
+            .header(super::CONTENT_LENGTH, "1048576")
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert_eq!(super::streaming_content_length(
+            &put("https://examplebucket.s3.amazonaws.com/key"), &resolver), Some(1048576));
+        assert_eq!(super::streaming_content_length(
+            &put("https://mfzwi23gnjvgw.mrap.accesspoint.s3-global.amazonaws.com/key"), &resolver),
+            None);
+    }
+
+    #[test]
+    fn test_sign_chunk() {
+        let mut signer = example_signer();
+        let body = vec![b'a'; 66560];
+        assert_eq!(signer.sign_chunk(&body[..65536]),
+            "ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648");
+        assert_eq!(signer.sign_chunk(&body[65536..]),
+            "0055627c9e194cb4542bae2aa5492e3c1575bbb81b612b7d234b86a503ef5497");
+        assert_eq!(signer.sign_chunk(&[]),
+            "b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9");
+    }
+
+    #[test]
+    fn test_chunked_encoder() {
+        let mut encoder = super::ChunkedEncoder::new(example_signer(), 65536);
+        let body = vec![b'a'; 66560];
+        let mut encoded = Vec::new();
+        for piece in body.chunks(5000) {
+            encoded.extend(encoder.push(piece));
+        }
+        encoded.extend(encoder.finish());
+        assert_eq!(encoded.len() as u64, super::encoded_content_length(66560, 65536));
+        assert!(encoded.starts_with(b"10000;chunk-signature=\
+            ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648\r\naaaa"));
+        assert!(encoded.ends_with(b"\r\n0;chunk-signature=\
+            b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9\r\n\r\n"));
+    }
+}
@@ -0,0 +1,430 @@
+//! Working out which partition, region and service a request to an AWS host is signed for.
+//!
+//! Most endpoints look like `<service>.<region>.<dns suffix>`, but there are plenty of variations
+//! on that: S3 virtual hosted buckets (`bucket.s3.us-west-2.amazonaws.com`), FIPS and dual-stack
+//! endpoints (`s3-fips.dualstack.us-east-1.amazonaws.com`), API Gateway and other endpoints with a
+//! resource prefix (`abc123.execute-api.us-east-1.amazonaws.com`), VPC interface endpoints
+//! (`vpce-0123abcd.ec2.us-east-1.vpce.amazonaws.com`), and global services like IAM that are
+//! signed for one fixed region.
+//!
+//! See https://docs.aws.amazon.com/general/latest/gr/rande.html
+
+use std::str::FromStr;
+
+/// The partition, region and service a request is signed for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub partition: String,
+    pub region: String,
+    pub service: String,
+}
+
+impl Endpoint {
+    fn new(partition: &str, region: &str, service: &str) -> Self {
+        Endpoint {
+            partition: partition.to_string(),
+            region: region.to_string(),
+            service: service.to_string(),
+        }
+    }
+}
+
+/// A group of regions that share DNS suffixes.
+struct Partition {
+    name: &'static str,
+    dns_suffixes: &'static [&'static str],
+    /// Every region in the partition starts with this.  Empty for the `aws` partition, which is
+    /// what any region that doesn't belong to another partition is in.
+    region_prefix: &'static str,
+    /// The region used for hosts that don't have one, like `ec2.amazonaws.com`.
+    default_region: &'static str,
+}
+
+const PARTITIONS: &[Partition] = &[
+    Partition {
+        name: "aws",
+        dns_suffixes: &["amazonaws.com", "api.aws"],
+        region_prefix: "",
+        default_region: "us-east-1",
+    },
+    Partition {
+        name: "aws-cn",
+        dns_suffixes: &["amazonaws.com.cn", "api.amazonwebservices.com.cn"],
+        region_prefix: "cn-",
+        default_region: "cn-north-1",
+    },
+    Partition {
+        name: "aws-us-gov",
+        dns_suffixes: &["amazonaws.com", "api.aws"],
+        region_prefix: "us-gov-",
+        default_region: "us-gov-west-1",
+    },
+    Partition {
+        name: "aws-iso",
+        dns_suffixes: &["c2s.ic.gov"],
+        region_prefix: "us-iso-",
+        default_region: "us-iso-east-1",
+    },
+    Partition {
+        name: "aws-iso-b",
+        dns_suffixes: &["sc2s.sgov.gov"],
+        region_prefix: "us-isob-",
+        default_region: "us-isob-east-1",
+    },
+];
+
+/// Services with a single endpoint for the whole partition, which are signed for a fixed region
+/// that can't be told from the host: `(host, partition, region, service)`.  Hosts under these
+/// (e.g. S3 buckets) match too.
+const GLOBAL_ENDPOINTS: &[(&str, &str, &str, &str)] = &[
+    ("iam.amazonaws.com", "aws", "us-east-1", "iam"),
+    ("iam.us-gov.amazonaws.com", "aws-us-gov", "us-gov-west-1", "iam"),
+    ("route53.amazonaws.com", "aws", "us-east-1", "route53"),
+    ("route53.amazonaws.com.cn", "aws-cn", "cn-northwest-1", "route53"),
+    ("route53.us-gov.amazonaws.com", "aws-us-gov", "us-gov-west-1", "route53"),
+    ("cloudfront.amazonaws.com", "aws", "us-east-1", "cloudfront"),
+    ("sts.amazonaws.com", "aws", "us-east-1", "sts"),
+    ("waf.amazonaws.com", "aws", "us-east-1", "waf"),
+    ("budgets.amazonaws.com", "aws", "us-east-1", "budgets"),
+    ("globalaccelerator.amazonaws.com", "aws", "us-west-2", "globalaccelerator"),
+    ("s3-external-1.amazonaws.com", "aws", "us-east-1", "s3"),
+];
+
+/// Services that are signed with a different name than their host says: `(labels, signing
+/// name)`, where the labels are the end of the host before the region, e.g. `data.iot` for
+/// `data.iot.us-east-1.amazonaws.com`.  A leading `*` in a label matches any prefix.
+const SIGNING_NAMES: &[(&str, &str)] = &[
+    ("email", "ses"),
+    ("data.iot", "iotdata"),
+    ("*-ats.iot", "iotdata"),
+    ("data.jobs.iot", "iot-jobs-data"),
+    ("api.tunneling.iot", "IoTSecuredTunneling"),
+    ("runtime.lex", "lex"),
+    ("models.lex", "lex"),
+    ("runtime-v2-lex", "lex"),
+    ("models-v2-lex", "lex"),
+    ("streams.dynamodb", "dynamodb"),
+    ("*.appsync-api", "appsync"),
+];
+
+/// Labels that only select a variant of an endpoint, e.g. `s3.dualstack.us-east-1.amazonaws.com`.
+const VARIANT_LABELS: &[&str] = &["dualstack", "fips"];
+
+/// Resolves the endpoint for `host`, or `None` if it isn't an AWS host.
+pub fn resolve(host: &str) -> Option<Endpoint> {
+    let host = host.trim_end_matches('.').to_lowercase();
+    for (global_host, partition, region, service) in GLOBAL_ENDPOINTS {
+        if host == *global_host || host.ends_with(&format!(".{}", global_host)) {
+            return Some(Endpoint::new(partition, region, service));
+        }
+    }
+
+    let suffix = PARTITIONS.iter()
+        .flat_map(|partition| partition.dns_suffixes.iter())
+        .filter(|suffix| host.ends_with(&format!(".{}", suffix)))
+        .max_by_key(|suffix| suffix.len())?;
+    let mut labels: Vec<&str> = host[..host.len() - suffix.len() - 1].split('.').collect();
+
+    // VPC interface endpoints add their id (and maybe an availability zone) in front of the usual
+    // host, and `vpce` before the suffix.
+    if labels.last() == Some(&"vpce") {
+        labels.pop();
+    }
+    if let Some(i) = labels.iter().rposition(|label| label.starts_with("vpce-")) {
+        labels.drain(..=i);
+    }
+
+    // The region is the last label, if there is one.  Old S3 endpoints put it in the same label
+    // as the service, like `s3-us-west-2.amazonaws.com`.
+    let last = *labels.last()?;
+    let (region, service) = if is_region(last) {
+        labels.pop();
+        while labels.len() > 1 && VARIANT_LABELS.contains(labels.last().unwrap()) {
+            labels.pop();
+        }
+        (Some(last), signing_name(&labels)?)
+    } else if last.starts_with("s3-") && is_region(&last[3..]) {
+        (Some(&last[3..]), String::from("s3"))
+    } else if labels.len() > 1 && is_region(labels[labels.len() - 2]) {
+        // OpenSearch domains and collections put the region before the service, like
+        // `search-mydomain-abc123.us-east-1.es.amazonaws.com`.
+        (Some(labels[labels.len() - 2]), signing_name(&labels[labels.len() - 1..])?)
+    } else {
+        (None, signing_name(&labels)?)
+    };
+
+    let candidates = PARTITIONS.iter()
+        .filter(|partition| partition.dns_suffixes.contains(suffix));
+    let partition = match region {
+        Some(region) => candidates
+            .filter(|partition| region.starts_with(partition.region_prefix))
+            .max_by_key(|partition| partition.region_prefix.len())?,
+        None => candidates.min_by_key(|partition| partition.region_prefix.len())?,
+    };
+    Some(Endpoint::new(partition.name, region.unwrap_or(partition.default_region), &service))
+}
+
+/// The DNS suffix of the partition `region` is in, e.g. `amazonaws.com.cn` for `cn-north-1`.
+pub fn dns_suffix(region: &str) -> &'static str {
+    PARTITIONS.iter()
+        .filter(|partition| region.starts_with(partition.region_prefix))
+        .max_by_key(|partition| partition.region_prefix.len())
+        .map_or("amazonaws.com", |partition| partition.dns_suffixes[0])
+}
+
+/// Region names look like `us-east-1`, `us-gov-west-1` or `cn-northwest-1`.
+fn is_region(label: &str) -> bool {
+    let parts: Vec<&str> = label.split('-').collect();
+    parts.len() >= 3
+        && parts[0].len() == 2
+        && parts[..parts.len() - 1].iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase()))
+        && parts[parts.len() - 1].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns the name requests are signed with, given the labels of the host up to and including
+/// the service (so without the region or DNS suffix).
+fn signing_name(labels: &[&str]) -> Option<String> {
+    for (pattern, name) in SIGNING_NAMES {
+        let pattern: Vec<&str> = pattern.split('.').collect();
+        if labels.len() >= pattern.len() && labels[labels.len() - pattern.len()..].iter()
+            .zip(pattern.iter())
+            .all(|(label, pattern)| matches_label(pattern, label)) {
+            return Some(name.to_string());
+        }
+    }
+    let label = labels.last()?.trim_end_matches("-fips");
+    match label {
+        // Access points, S3 Control and the global S3 endpoints are all signed as S3.
+        "s3-accesspoint" | "s3-control" | "s3-global" => Some(String::from("s3")),
+        _ => Some(label.to_string()),
+    }
+}
+
+/// Whether `host` matches `pattern`, where a leading `*` matches any prefix, e.g.
+/// `*.amazonaws.com`.  Patterns are expected to be lowercase.
+pub fn matches_host(pattern: &str, host: &str) -> bool {
+    matches_label(pattern, &host.trim_end_matches('.').to_lowercase())
+}
+
+/// A leading `*` matches any prefix, so `*.example.com` matches `api.example.com` and
+/// `*-ats` matches `a1b2c3-ats`.
+fn matches_label(pattern: &str, label: &str) -> bool {
+    if pattern.starts_with('*') {
+        label.ends_with(&pattern[1..])
+    } else {
+        label == pattern
+    }
+}
+
+/// A service name and/or region to sign with for hosts matching a pattern, for services whose
+/// host doesn't say how to sign for them (e.g. API Gateway custom domains).
+///
+/// Parsed from `HOST_PATTERN=SERVICE:REGION`, where either `SERVICE` or `:REGION` may be left
+/// out to keep what is resolved from the host, e.g. `api.example.com=execute-api:us-west-2` or
+/// `*.example.com=:eu-west-1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningOverride {
+    host_pattern: String,
+    service: Option<String>,
+    region: Option<String>,
+}
+
+impl SigningOverride {
+    pub fn new(host_pattern: &str, service: Option<&str>, region: Option<&str>) -> Self {
+        SigningOverride {
+            host_pattern: host_pattern.to_lowercase(),
+            service: service.map(String::from),
+            region: region.map(String::from),
+        }
+    }
+}
+
+impl FromStr for SigningOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.find('=')
+            .ok_or_else(|| format!("expected HOST_PATTERN=SERVICE:REGION, got {:?}", s))?;
+        let (host_pattern, signing) = (&s[..i], &s[i + 1..]);
+        let mut parts = signing.splitn(2, ':');
+        let service = parts.next().filter(|service| !service.is_empty()).map(String::from);
+        let region = parts.next().filter(|region| !region.is_empty()).map(String::from);
+        if host_pattern.is_empty() || (service.is_none() && region.is_none()) {
+            return Err(format!("expected HOST_PATTERN=SERVICE:REGION, got {:?}", s));
+        }
+        Ok(SigningOverride { host_pattern: host_pattern.to_lowercase(), service, region })
+    }
+}
+
+/// The hosts requests may be signed for, so that credentials are never sent anywhere else.
+#[derive(Debug, Clone)]
+pub struct HostAllowlist {
+    patterns: Vec<String>,
+}
+
+/// Every AWS endpoint, in all partitions.
+pub const DEFAULT_ALLOWED_HOSTS: &[&str] = &["*.amazonaws.com", "*.amazonaws.com.cn", "*.api.aws"];
+
+impl HostAllowlist {
+    /// `patterns` are host names, where a leading `*` matches any prefix.
+    pub fn new(patterns: Vec<String>) -> Self {
+        HostAllowlist {
+            patterns: patterns.into_iter().map(|pattern| pattern.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        self.patterns.iter().any(|pattern| matches_host(pattern, host))
+    }
+}
+
+impl Default for HostAllowlist {
+    fn default() -> Self {
+        HostAllowlist::new(DEFAULT_ALLOWED_HOSTS.iter().map(|pattern| pattern.to_string())
+            .collect())
+    }
+}
+
+/// Resolves endpoints with `resolve`, and then applies the first `SigningOverride` that matches
+/// the host.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointResolver {
+    overrides: Vec<SigningOverride>,
+}
+
+impl EndpointResolver {
+    pub fn new(overrides: Vec<SigningOverride>) -> Self {
+        EndpointResolver { overrides }
+    }
+
+    /// Hosts that aren't AWS endpoints and don't have an override are signed for the service
+    /// named by their first label in `us-east-1`, which is the best we can do without being told.
+    pub fn resolve(&self, host: &str) -> Endpoint {
+        let mut endpoint = resolve(host).unwrap_or_else(|| Endpoint::new("aws", "us-east-1",
+            host.split('.').next().unwrap_or("")));
+        let matching = self.overrides.iter()
+            .find(|o| matches_host(&o.host_pattern, host));
+        if let Some(signing_override) = matching {
+            if let Some(service) = &signing_override.service {
+                endpoint.service = service.clone();
+            }
+            if let Some(region) = &signing_override.region {
+                endpoint.region = region.clone();
+            }
+        }
+        endpoint
+    }
+
+    /// Whether the host was configured with a `SigningOverride`, which means it's meant to be
+    /// signed for even if it isn't an AWS endpoint.
+    pub fn has_override(&self, host: &str) -> bool {
+        self.overrides.iter().any(|o| matches_host(&o.host_pattern, host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_resolve() {
+        let cases = [
+            ("ec2.amazonaws.com", "aws", "us-east-1", "ec2"),
+            ("ec2.eu-west-1.amazonaws.com", "aws", "eu-west-1", "ec2"),
+            ("bucket.s3.us-west-2.amazonaws.com", "aws", "us-west-2", "s3"),
+            ("bucket.s3.amazonaws.com", "aws", "us-east-1", "s3"),
+            ("bucket.s3-us-west-2.amazonaws.com", "aws", "us-west-2", "s3"),
+            ("bucket.s3-external-1.amazonaws.com", "aws", "us-east-1", "s3"),
+            ("s3-fips.dualstack.us-east-2.amazonaws.com", "aws", "us-east-2", "s3"),
+            ("s3.dualstack.ap-south-1.amazonaws.com", "aws", "ap-south-1", "s3"),
+            ("ap-123456789012.s3-accesspoint.eu-central-1.amazonaws.com", "aws", "eu-central-1",
+                "s3"),
+            ("abc123.execute-api.us-east-1.amazonaws.com", "aws", "us-east-1", "execute-api"),
+            ("runtime.sagemaker.us-west-2.amazonaws.com", "aws", "us-west-2", "sagemaker"),
+            ("kms-fips.us-east-1.amazonaws.com", "aws", "us-east-1", "kms"),
+            ("ec2.us-east-1.api.aws", "aws", "us-east-1", "ec2"),
+            ("dynamodb.cn-north-1.amazonaws.com.cn", "aws-cn", "cn-north-1", "dynamodb"),
+            ("bucket.s3.cn-northwest-1.amazonaws.com.cn", "aws-cn", "cn-northwest-1", "s3"),
+            ("sts.us-gov-west-1.amazonaws.com", "aws-us-gov", "us-gov-west-1", "sts"),
+            ("ec2.us-iso-east-1.c2s.ic.gov", "aws-iso", "us-iso-east-1", "ec2"),
+            ("vpce-0123abcd-4567efgh.ec2.us-east-1.vpce.amazonaws.com", "aws", "us-east-1",
+                "ec2"),
+            ("bucket.vpce-0123abcd-4567efgh.s3.us-east-1.vpce.amazonaws.com", "aws", "us-east-1",
+                "s3"),
+            ("vpce-0123abcd-4567efgh-us-east-1a.sqs.us-east-1.vpce.amazonaws.com", "aws",
+                "us-east-1", "sqs"),
+            ("iam.amazonaws.com", "aws", "us-east-1", "iam"),
+            ("iam.us-gov.amazonaws.com", "aws-us-gov", "us-gov-west-1", "iam"),
+            ("route53.amazonaws.com", "aws", "us-east-1", "route53"),
+            ("route53.amazonaws.com.cn", "aws-cn", "cn-northwest-1", "route53"),
+            ("cloudfront.amazonaws.com", "aws", "us-east-1", "cloudfront"),
+            ("EC2.US-WEST-1.AMAZONAWS.COM.", "aws", "us-west-1", "ec2"),
+            ("email.eu-west-1.amazonaws.com", "aws", "eu-west-1", "ses"),
+            ("data.iot.us-east-1.amazonaws.com", "aws", "us-east-1", "iotdata"),
+            ("a1b2c3d4e5f6g7-ats.iot.us-east-1.amazonaws.com", "aws", "us-east-1", "iotdata"),
+            ("iot.us-east-1.amazonaws.com", "aws", "us-east-1", "iot"),
+            ("runtime.lex.us-west-2.amazonaws.com", "aws", "us-west-2", "lex"),
+            ("streams.dynamodb.us-east-1.amazonaws.com", "aws", "us-east-1", "dynamodb"),
+            ("monitoring.us-east-1.amazonaws.com", "aws", "us-east-1", "monitoring"),
+            ("search-mydomain-abc123.us-west-2.es.amazonaws.com", "aws", "us-west-2", "es"),
+            ("abc123.eu-west-1.aoss.amazonaws.com", "aws", "eu-west-1", "aoss"),
+        ];
+        for (host, partition, region, service) in cases.iter() {
+            assert_eq!(super::resolve(host), Some(super::Endpoint::new(partition, region, service)),
+                "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_dns_suffix() {
+        assert_eq!(super::dns_suffix("us-west-2"), "amazonaws.com");
+        assert_eq!(super::dns_suffix("us-gov-west-1"), "amazonaws.com");
+        assert_eq!(super::dns_suffix("cn-northwest-1"), "amazonaws.com.cn");
+        assert_eq!(super::dns_suffix("us-iso-east-1"), "c2s.ic.gov");
+    }
+
+    #[test]
+    fn test_resolve_non_aws_hosts() {
+        assert_eq!(super::resolve("localhost"), None);
+        assert_eq!(super::resolve("example.com"), None);
+        assert_eq!(super::resolve("amazonaws.com"), None);
+        assert_eq!(super::resolve("notamazonaws.com"), None);
+    }
+
+    #[test]
+    fn test_signing_overrides() {
+        let resolver = super::EndpointResolver::new(vec![
+            "api.example.com=execute-api:us-west-2".parse().unwrap(),
+            "*.es.amazonaws.com=aoss".parse().unwrap(),
+            "*.example.com=:eu-west-1".parse().unwrap(),
+        ]);
+        assert_eq!(resolver.resolve("api.example.com"),
+            super::Endpoint::new("aws", "us-west-2", "execute-api"));
+        assert_eq!(resolver.resolve("search-x.us-west-2.es.amazonaws.com"),
+            super::Endpoint::new("aws", "us-west-2", "aoss"));
+        assert_eq!(resolver.resolve("minio.example.com"),
+            super::Endpoint::new("aws", "eu-west-1", "minio"));
+        assert_eq!(resolver.resolve("ec2.amazonaws.com"),
+            super::Endpoint::new("aws", "us-east-1", "ec2"));
+        assert!("api.example.com".parse::<super::SigningOverride>().is_err());
+        assert!("api.example.com=".parse::<super::SigningOverride>().is_err());
+        assert!("=execute-api".parse::<super::SigningOverride>().is_err());
+        assert!(resolver.has_override("API.example.com"));
+        assert!(!resolver.has_override("ec2.amazonaws.com"));
+    }
+
+    #[test]
+    fn test_host_allowlist() {
+        let allowlist = super::HostAllowlist::default();
+        assert!(allowlist.allows("ec2.amazonaws.com"));
+        assert!(allowlist.allows("bucket.s3.us-west-2.amazonaws.com."));
+        assert!(allowlist.allows("dynamodb.cn-north-1.amazonaws.com.cn"));
+        assert!(allowlist.allows("EC2.us-east-1.api.aws"));
+        assert!(!allowlist.allows("example.com"));
+        assert!(!allowlist.allows("amazonaws.com.example.com"));
+        assert!(!allowlist.allows("notamazonaws.com"));
+        let allowlist = super::HostAllowlist::new(vec![String::from("localhost")]);
+        assert!(allowlist.allows("localhost"));
+        assert!(!allowlist.allows("ec2.amazonaws.com"));
+    }
+}
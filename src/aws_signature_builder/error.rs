@@ -0,0 +1,54 @@
+//! The ways signing a request can fail.
+
+use openssl::error::ErrorStack;
+use rusoto_credential::CredentialsError;
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SigningError {
+    /// The request uri has no host, so there is nothing to sign it for.
+    MissingHost(String),
+    /// A header we need to set on the request can't be represented as a header value.
+    InvalidHeaderValue { name: String, value: String },
+    /// The credentials to sign with could not be loaded.
+    Credentials(CredentialsError),
+    /// Deriving the SigV4a key or signing with it failed.
+    Crypto(ErrorStack),
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SigningError::MissingHost(uri) => write!(f, "can't sign a request without a host: {}",
+                uri),
+            SigningError::InvalidHeaderValue { name, value } => write!(f,
+                "can't set the {} header to {:?}", name, value),
+            SigningError::Credentials(e) => write!(f, "failed to load AWS credentials: {}", e),
+            SigningError::Crypto(e) => write!(f, "failed to calculate the signature: {}", e),
+        }
+    }
+}
+
+impl Error for SigningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SigningError::Credentials(e) => Some(e),
+            SigningError::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CredentialsError> for SigningError {
+    fn from(e: CredentialsError) -> Self {
+        SigningError::Credentials(e)
+    }
+}
+
+impl From<ErrorStack> for SigningError {
+    fn from(e: ErrorStack) -> Self {
+        SigningError::Crypto(e)
+    }
+}
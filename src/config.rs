@@ -0,0 +1,225 @@
+//! A TOML file with the same settings as the command line, so a team can check in the
+//! configuration of a shared proxy and start it with `--config`.
+//!
+//! Every option is set under its name with underscores instead of dashes, with the same values it
+//! takes on the command line, e.g. `allow_host = ["*.amazonaws.com"]` or
+//! `signing_override = ["api.example.com=execute-api:us-west-2"]`.  Options that can be given more
+//! than once are lists, except for `role_tag`, which is a table of tags.  Anything on the command
+//! line wins over the file, and unknown settings are refused so typos don't go unnoticed.  Flags
+//! the file turns on can be turned off again on the command line with their `--no-` form, e.g.
+//! `--no-reject-other-hosts`.
+//!
+//! The file is read again when the proxy reloads on `SIGHUP`, see `signals`.
+
+use serde::{de, Deserialize, Deserializer};
+
+use aws_signature_proxy::aws_signature_builder::endpoints::SigningOverride;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::credentials::clients::ClientSpec;
+use crate::egress::UpstreamProxy;
+use crate::listen::{self, Listen};
+use crate::routes::{Route, Upstream};
+use crate::Cli;
+
+/// A value written the same way as on the command line.
+struct Parsed<T>(T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T> where T: FromStr, T::Err: fmt::Display {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map(Parsed).map_err(de::Error::custom)
+    }
+}
+
+/// A file mode, either in octal like on the command line (`"660"`) or as a TOML number (`0o660`).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SocketMode {
+    Octal(String),
+    Number(u32),
+}
+
+impl SocketMode {
+    fn mode(&self) -> Result<u32, String> {
+        match self {
+            SocketMode::Octal(mode) => listen::parse_socket_mode(mode),
+            SocketMode::Number(mode) if *mode <= 0o777 => Ok(*mode),
+            SocketMode::Number(mode) => Err(format!(
+                "expected a file mode like \"660\" or 0o660, got {:#o}", mode)),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    port: Option<u16>,
+    listen: Vec<Parsed<Listen>>,
+    socket_mode: Option<SocketMode>,
+    unsigned_payload: Vec<String>,
+    sign_header: Vec<String>,
+    signing_override: Vec<Parsed<SigningOverride>>,
+    allow_host: Vec<String>,
+    reject_other_hosts: bool,
+    reverse_proxy: bool,
+    route: Vec<Parsed<Route>>,
+    upstream: Vec<Parsed<Upstream>>,
+    profile: Option<String>,
+    role_arn: Vec<String>,
+    external_id: Option<String>,
+    role_session_name: Option<String>,
+    role_duration_seconds: Option<u32>,
+    role_tag: BTreeMap<String, String>,
+    sts_endpoint: Option<String>,
+    upstream_proxy: Option<Parsed<UpstreamProxy>>,
+    mfa_serial: Option<String>,
+    client: Vec<Parsed<ClientSpec>>,
+    metadata_port: Option<u16>,
+    container_authorization_token: Option<String>,
+    ca_dir: Option<PathBuf>,
+    quiet: bool,
+    shutdown_timeout: Option<u64>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        Config::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        if let Some(socket_mode) = &config.socket_mode {
+            socket_mode.mode().map_err(|e| format!("socket_mode: {}", e))?;
+        }
+        Ok(config)
+    }
+
+    /// Fills in everything `args` doesn't already set.
+    pub fn apply(self, args: &mut Cli) {
+        option(&mut args.port, self.port);
+        list(&mut args.listen, self.listen);
+        if args.socket_mode.is_none() {
+            // Checked by `parse`.
+            args.socket_mode = self.socket_mode.and_then(|socket_mode| socket_mode.mode().ok());
+        }
+        list(&mut args.unsigned_payload, strings(self.unsigned_payload));
+        list(&mut args.sign_header, strings(self.sign_header));
+        list(&mut args.signing_override, self.signing_override);
+        list(&mut args.allow_host, strings(self.allow_host));
+        flag(&mut args.reject_other_hosts, args.no_reject_other_hosts, self.reject_other_hosts);
+        flag(&mut args.reverse_proxy, args.no_reverse_proxy, self.reverse_proxy);
+        list(&mut args.route, self.route);
+        list(&mut args.upstream, self.upstream);
+        option(&mut args.profile, self.profile);
+        list(&mut args.role_arn, strings(self.role_arn));
+        option(&mut args.external_id, self.external_id);
+        option(&mut args.role_session_name, self.role_session_name);
+        option(&mut args.role_duration_seconds, self.role_duration_seconds);
+        if args.role_tag.is_empty() {
+            args.role_tag = self.role_tag.into_iter().collect();
+        }
+        option(&mut args.sts_endpoint, self.sts_endpoint);
+        option(&mut args.upstream_proxy, self.upstream_proxy.map(|proxy| proxy.0));
+        option(&mut args.mfa_serial, self.mfa_serial);
+        list(&mut args.client, self.client);
+        option(&mut args.metadata_port, self.metadata_port);
+        option(&mut args.container_authorization_token, self.container_authorization_token);
+        option(&mut args.ca_dir, self.ca_dir);
+        flag(&mut args.quiet, args.no_quiet, self.quiet);
+        option(&mut args.shutdown_timeout, self.shutdown_timeout);
+    }
+}
+
+fn option<T>(arg: &mut Option<T>, value: Option<T>) {
+    if arg.is_none() {
+        *arg = value;
+    }
+}
+
+/// The file can only turn flags on, unless they are turned off on the command line.
+fn flag(arg: &mut bool, turned_off: bool, value: bool) {
+    *arg = !turned_off && (*arg || value);
+}
+
+/// Options given more than once on the command line replace the file's list instead of adding to
+/// it, since for some (like `role_arn`) the order matters.
+fn list<T>(arg: &mut Vec<T>, values: Vec<Parsed<T>>) {
+    if arg.is_empty() {
+        *arg = values.into_iter().map(|value| value.0).collect();
+    }
+}
+
+fn strings(values: Vec<String>) -> Vec<Parsed<String>> {
+    values.into_iter().map(Parsed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    const CONFIG: &str = r#"
+listen = ["0.0.0.0:8080", "unix:/run/aws-signature-proxy.sock"]
+socket_mode = "660"
+allow_host = ["*.amazonaws.com", "api.example.com"]
+reject_other_hosts = true
+signing_override = ["api.example.com=execute-api:us-west-2"]
+unsigned_payload = ["s3"]
+profile = "dev"
+role_arn = ["arn:aws:iam::123456789012:role/readonly"]
+role_tag = { Project = "proxy", Team = "platform" }
+client = ["ci:s3cret=arn:aws:iam::123456789012:role/ci"]
+quiet = true
+"#;
+
+    #[test]
+    fn test_apply_config() {
+        let config = super::Config::parse(CONFIG).unwrap();
+        let mut args = crate::Cli::from_iter(&["aws-signature-proxy", "--profile", "admin"]);
+        config.apply(&mut args);
+        assert_eq!(args.listen.len(), 2);
+        assert_eq!(args.socket_mode, Some(0o660));
+        assert_eq!(args.allow_host, vec!["*.amazonaws.com", "api.example.com"]);
+        assert!(args.reject_other_hosts);
+        assert!(!args.reverse_proxy);
+        assert_eq!(args.signing_override.len(), 1);
+        // The command line wins.
+        assert_eq!(args.profile, Some(String::from("admin")));
+        assert_eq!(args.role_tag, vec![
+            (String::from("Project"), String::from("proxy")),
+            (String::from("Team"), String::from("platform")),
+        ]);
+        assert_eq!(args.client[0].name, "ci");
+        assert!(args.quiet);
+        assert!(args.port.is_none());
+
+        let config = super::Config::parse(CONFIG).unwrap();
+        let mut args = crate::Cli::from_iter(&["aws-signature-proxy", "--no-reject-other-hosts",
+            "--no-quiet", "--socket-mode", "600"]);
+        config.apply(&mut args);
+        assert!(!args.reject_other_hosts);
+        assert!(!args.quiet);
+        assert_eq!(args.socket_mode, Some(0o600));
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let e = super::Config::parse("allow_hosts = [\"*.amazonaws.com\"]").err().unwrap();
+        assert!(e.contains("allow_hosts"), "{}", e);
+        let e = super::Config::parse("route = [\"s3\"]").err().unwrap();
+        assert!(e.contains("NAME=BASE_URL"), "{}", e);
+        assert!(super::Config::parse("port = \"8080\"").is_err());
+        assert!(super::Config::parse("socket_mode = \"999\"").is_err());
+        let config = super::Config::parse("socket_mode = 0o660").unwrap();
+        assert_eq!(config.socket_mode.unwrap().mode(), Ok(0o660));
+        let e = super::Config::parse("socket_mode = 660").err().unwrap();
+        assert!(e.contains("0o1224"), "{}", e);
+        assert!(super::Config::parse("").is_ok());
+    }
+}
@@ -0,0 +1,156 @@
+//! Identities the clients of a shared proxy can pick, so that everyone using it doesn't have to
+//! sign as the same user.
+//!
+//! A client picks one by sending its name and token as `Proxy-Authorization: Basic` (what `curl
+//! --proxy-user NAME:TOKEN` does), or as an `X-Proxy-Profile: NAME:TOKEN` header for clients that
+//! can't set proxy credentials.  Neither header is forwarded.
+
+use http::header::HeaderValue;
+use rusoto_credential::CredentialsError;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::CredentialsCache;
+
+pub const XPROXYPROFILE: &str = "x-proxy-profile";
+
+/// A client identity from the command line, as `NAME:TOKEN` for the profile `NAME`, or as
+/// `NAME:TOKEN=ROLE_ARN` for a role assumed with the proxy's own credentials.
+#[derive(Debug, Clone)]
+pub struct ClientSpec {
+    pub name: String,
+    pub token: String,
+    pub role_arn: Option<String>,
+}
+
+impl FromStr for ClientSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client, role_arn) = match s.find('=') {
+            Some(i) => (&s[..i], Some(s[i + 1..].to_string())),
+            None => (s, None),
+        };
+        match client.find(':') {
+            Some(i) if i > 0 && i + 1 < client.len() => Ok(ClientSpec {
+                name: client[..i].to_string(),
+                token: client[i + 1..].to_string(),
+                role_arn,
+            }),
+            _ => Err(format!("expected NAME:TOKEN or NAME:TOKEN=ROLE_ARN, got {:?}", s)),
+        }
+    }
+}
+
+struct ClientIdentity {
+    token: String,
+    credentials: CredentialsCache,
+}
+
+/// The identities clients can pick.  Without any, every request is signed with the proxy's own
+/// credentials.
+#[derive(Default)]
+pub struct Clients {
+    identities: HashMap<String, ClientIdentity>,
+}
+
+impl Clients {
+    pub fn insert(&mut self, name: &str, token: &str, credentials: CredentialsCache) {
+        self.identities.insert(name.to_string(),
+            ClientIdentity { token: token.to_string(), credentials });
+    }
+
+    /// Returns the credentials of the client that sent `proxy_authorization` or `profile_header`,
+    /// or `None` if clients don't pick identities on this proxy.  Once there are identities, a
+    /// request has to name one with the right token.
+    pub fn identify(
+        &self,
+        proxy_authorization: Option<&HeaderValue>,
+        profile_header: Option<&HeaderValue>)
+        -> Result<Option<&CredentialsCache>, CredentialsError> {
+        if self.identities.is_empty() {
+            return Ok(None);
+        }
+        let (name, token) = match (profile_header, proxy_authorization) {
+            (Some(value), _) => value.to_str().ok().and_then(split_name_and_token),
+            (None, Some(value)) => basic_credentials(value),
+            (None, None) => return Err(CredentialsError::new(
+                "this proxy requires a client identity as NAME:TOKEN")),
+        }.ok_or_else(|| CredentialsError::new("client identity is not NAME:TOKEN"))?;
+        match self.identities.get(&name) {
+            Some(identity) if tokens_match(&identity.token, &token) =>
+                Ok(Some(&identity.credentials)),
+            _ => Err(CredentialsError::new(format!("wrong token for client identity {}", name))),
+        }
+    }
+}
+
+/// Decodes `Basic base64(NAME:TOKEN)`.
+fn basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let value = value.to_str().ok()?.trim();
+    let i = value.find(' ')?;
+    if !value[..i].eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = openssl::base64::decode_block(value[i..].trim()).ok()?;
+    split_name_and_token(&String::from_utf8(decoded).ok()?)
+}
+
+fn split_name_and_token(value: &str) -> Option<(String, String)> {
+    let i = value.find(':')?;
+    Some((value[..i].to_string(), value[i + 1..].to_string()))
+}
+
+/// Compares tokens in constant time, so how long the comparison takes doesn't give them away.
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len() && openssl::memcmp::eq(expected.as_bytes(), actual.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use http::header::HeaderValue;
+    use rusoto_credential::StaticProvider;
+
+    use crate::credentials::CredentialsCache;
+
+    fn clients() -> super::Clients {
+        let mut clients = super::Clients::default();
+        clients.insert("dev", "s3cret", CredentialsCache::new(
+            Box::new(StaticProvider::new_minimal(String::from("AKIDDEV"), String::from("dev")))));
+        clients
+    }
+
+    fn access_key_id(credentials: Option<&CredentialsCache>) -> String {
+        credentials.unwrap().credentials().wait().unwrap().aws_access_key_id().to_string()
+    }
+
+    #[test]
+    fn test_parse_client_spec() {
+        let spec: super::ClientSpec = "dev:s3cret".parse().unwrap();
+        assert_eq!((spec.name.as_str(), spec.token.as_str()), ("dev", "s3cret"));
+        assert_eq!(spec.role_arn, None);
+        let spec: super::ClientSpec = "ops:t0ken=arn:aws:iam::123456789012:role/ops".parse()
+            .unwrap();
+        assert_eq!((spec.name.as_str(), spec.token.as_str()), ("ops", "t0ken"));
+        assert_eq!(spec.role_arn.as_ref().map(String::as_str),
+            Some("arn:aws:iam::123456789012:role/ops"));
+        assert!("dev".parse::<super::ClientSpec>().is_err());
+        assert!("dev:".parse::<super::ClientSpec>().is_err());
+    }
+
+    #[test]
+    fn test_identify() {
+        let clients = clients();
+        // "dev:s3cret"
+        let basic = HeaderValue::from_static("Basic ZGV2OnMzY3JldA==");
+        assert_eq!(access_key_id(clients.identify(Some(&basic), None).unwrap()), "AKIDDEV");
+        let header = HeaderValue::from_static("dev:s3cret");
+        assert_eq!(access_key_id(clients.identify(None, Some(&header)).unwrap()), "AKIDDEV");
+        let wrong = HeaderValue::from_static("dev:guess");
+        assert!(clients.identify(None, Some(&wrong)).is_err());
+        assert!(clients.identify(None, None).is_err());
+        assert!(super::Clients::default().identify(None, None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,194 @@
+//! Roles that can only be assumed with a code from an MFA device, configured with `mfa_serial`.
+//!
+//! The first code can be given with `--mfa-token`, and later ones are asked for on the terminal.
+//! Since every session needs a new code, sessions are also kept on disk in the same format as the
+//! AWS CLI's cache, so restarting the proxy doesn't mean typing in another code.
+
+use chrono::{Duration, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use futures::future::{self, Future};
+use futures::sync::oneshot;
+use rusoto_credential::{AwsCredentials, CredentialsError};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::sts::{AssumeRole, StsClient};
+use super::{CredentialsFuture, CredentialsSource};
+
+/// Where MFA codes come from.  Shared by every role, so only one code is asked for at a time.
+#[derive(Default)]
+pub struct MfaCodes {
+    /// The code given on the command line, until it has been used.
+    token: Mutex<Option<String>>,
+}
+
+impl MfaCodes {
+    pub fn new(token: Option<String>) -> Self {
+        MfaCodes { token: Mutex::new(token) }
+    }
+
+    /// Returns a code for the device `serial_number`, waiting for someone to type it in if the one
+    /// from the command line has been used already.
+    fn code(&self, serial_number: &str) -> Result<String, CredentialsError> {
+        let mut token = self.token.lock().unwrap();
+        match token.take() {
+            Some(token) => Ok(token),
+            None => prompt(serial_number),
+        }
+    }
+}
+
+fn prompt(serial_number: &str) -> Result<String, CredentialsError> {
+    let no_terminal = |e| CredentialsError::new(format!(
+        "can't ask for an MFA code for {} ({}), use --mfa-token", serial_number, e));
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(no_terminal)?;
+    write!(tty, "Enter MFA code for {}: ", serial_number).map_err(no_terminal)?;
+    let mut code = String::new();
+    BufReader::new(&tty).read_line(&mut code).map_err(no_terminal)?;
+    Ok(code.trim().to_string())
+}
+
+/// Credentials for a role that requires MFA, assumed with the credentials from `base` and reused
+/// from the disk cache while they last.  `base_name` says where `base` comes from (e.g. its
+/// profile), since sessions assumed with other credentials are cached separately.
+pub struct MfaRoleSource {
+    sts: StsClient,
+    base: Box<dyn CredentialsSource>,
+    role: AssumeRole,
+    codes: Arc<MfaCodes>,
+    cache: Option<SessionCache>,
+}
+
+impl MfaRoleSource {
+    pub fn new(
+        sts: StsClient,
+        base: Box<dyn CredentialsSource>,
+        base_name: &str,
+        role: AssumeRole,
+        codes: Arc<MfaCodes>) -> Self {
+        let cache = SessionCache::new(base_name, &role)
+            .map_err(|e| eprintln!("not caching MFA sessions: {}", e))
+            .ok();
+        MfaRoleSource { sts, base, role, codes, cache }
+    }
+}
+
+impl CredentialsSource for MfaRoleSource {
+    fn load(&self) -> CredentialsFuture {
+        if let Some(credentials) = self.cache.as_ref().and_then(SessionCache::load) {
+            return Box::new(future::ok(credentials));
+        }
+        let serial_number = self.role.serial_number.clone().unwrap_or_default();
+        let codes = self.codes.clone();
+        let (sender, receiver) = oneshot::channel();
+        // Typing in the code can take a while, so wait for it on another thread.
+        thread::spawn(move || sender.send(codes.code(&serial_number)));
+        let code = receiver
+            .map_err(|_| CredentialsError::new("interrupted while waiting for an MFA code"))
+            .and_then(|code| code);
+        let sts = self.sts.clone();
+        let mut role = self.role.clone();
+        let cache = self.cache.clone();
+        Box::new(self.base.load().join(code)
+            .and_then(move |(credentials, code)| {
+                role.token_code = Some(code);
+                sts.assume_role(credentials, &role)
+            })
+            .map(move |credentials| {
+                if let Some(cache) = cache {
+                    cache.store(&credentials);
+                }
+                credentials
+            }))
+    }
+}
+
+/// A file with the last session of a role, in `~/.aws/aws-signature-proxy/cache`.
+#[derive(Clone)]
+struct SessionCache {
+    path: PathBuf,
+}
+
+impl SessionCache {
+    /// The file is named after everything that decides who the session belongs to.
+    fn new(base_name: &str, role: &AssumeRole) -> Result<Self, CredentialsError> {
+        let mut hasher = Sha256::new();
+        hasher.input_str(&format!("{}\n{}\n{}\n{}", base_name, role.role_arn,
+            role.serial_number.as_ref().map(String::as_str).unwrap_or_default(),
+            role.external_id.as_ref().map(String::as_str).unwrap_or_default()));
+        let path = super::profile::aws_dir()?.join("aws-signature-proxy").join("cache")
+            .join(format!("{}.json", hasher.result_str()));
+        Ok(SessionCache { path })
+    }
+
+    /// Returns the cached session, unless it's about to expire.
+    fn load(&self) -> Option<AwsCredentials> {
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&self.path).ok()?).ok()?;
+        let credentials = super::credentials_from_json(&json["Credentials"]).ok()?;
+        let expires_at = (*credentials.expires_at())?;
+        if expires_at < Utc::now() + Duration::seconds(super::REFRESH_BEFORE_EXPIRY) {
+            return None;
+        }
+        Some(credentials)
+    }
+
+    /// Saves the session where only the current user can read it.  Failing to is only logged,
+    /// since that just means asking for another code next time.
+    fn store(&self, credentials: &AwsCredentials) {
+        let json = serde_json::json!({ "Credentials": super::credentials_to_json(credentials) });
+        if let Err(e) = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| create_private(&self.path))
+            .and_then(|mut file| file.write_all(json.to_string().as_bytes())) {
+            eprintln!("failed to cache the MFA session in {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_private(path: &PathBuf) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &PathBuf) -> std::io::Result<File> {
+    File::create(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use rusoto_credential::AwsCredentials;
+
+    use crate::credentials::sts::AssumeRole;
+
+    #[test]
+    fn test_session_cache() {
+        let dir = std::env::temp_dir().join(format!("mfa-cache-test-{}", std::process::id()));
+        let mut role = AssumeRole::new("arn:aws:iam::123456789012:role/admin");
+        role.serial_number = Some(String::from("arn:aws:iam::123456789012:mfa/me"));
+        let cache = super::SessionCache { path: dir.join("session.json") };
+        assert!(cache.load().is_none());
+        cache.store(&AwsCredentials::new("ASIA1", "secret", Some(String::from("token")),
+            Some(Utc::now() + Duration::hours(1))));
+        let credentials = cache.load().unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "ASIA1");
+        assert_eq!(credentials.token(), &Some(String::from("token")));
+        // Sessions that are about to expire are not worth reusing.
+        cache.store(&AwsCredentials::new("ASIA2", "secret", None,
+            Some(Utc::now() + Duration::seconds(10))));
+        assert!(cache.load().is_none());
+        // Other roles, devices and base credentials get other files.
+        let path = super::SessionCache::new("dev", &role).unwrap().path;
+        assert_eq!(super::SessionCache::new("dev", &role).unwrap().path, path);
+        assert_ne!(super::SessionCache::new("prod", &role).unwrap().path, path);
+        role.serial_number = None;
+        assert_ne!(super::SessionCache::new("dev", &role).unwrap().path, path);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+//! The credentials requests are signed with.
+//!
+//! Loading credentials can mean reading files or asking the instance metadata service, so they are
+//! loaded once and kept in a `CredentialsCache` shared by every request.  Credentials that expire
+//! are refreshed in the background a few minutes before they do, so requests don't have to wait
+//! for the refresh, and a failed refresh keeps the old credentials for as long as they are valid.
+//! After a failure, loading isn't tried again for a few seconds.
+//!
+//! Where the credentials come from is a `CredentialsSource`: rusoto's default chain (environment,
+//! default profile, container or instance metadata), a named `profile`, a role assumed through
+//! `sts` (with other credentials or with a web identity token), or a `process` configured in a
+//! profile.  Roles that require MFA are assumed with codes from `mfa`.  Clients of a shared proxy
+//! can also pick one of several identities, see `clients`.
+
+pub mod clients;
+pub mod mfa;
+pub mod process;
+pub mod profile;
+pub mod sts;
+
+use chrono::{DateTime, Duration, Utc};
+use futures::future::{self, Future, Shared};
+use rusoto_credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub type CredentialsFuture =
+    Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
+
+/// Start refreshing credentials in the background once they expire in less than this many
+/// seconds.
+const REFRESH_BEFORE_EXPIRY: i64 = 5 * 60;
+
+/// Stop using credentials and wait for new ones once they expire in less than this many seconds,
+/// so they don't expire while the request is on its way.
+const MIN_REMAINING_LIFETIME: i64 = 60;
+
+/// Wait this many seconds after a failed load before trying again, so that when the source is
+/// down (e.g. STS) every request doesn't make another attempt.
+const MIN_RETRY_INTERVAL: i64 = 5;
+
+/// Anything that can load credentials.  Every rusoto provider is one, e.g. `ChainProvider`.
+pub trait CredentialsSource: Send + Sync + 'static {
+    fn load(&self) -> CredentialsFuture;
+}
+
+impl<P> CredentialsSource for P
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    P::Future: Send,
+{
+    fn load(&self) -> CredentialsFuture {
+        Box::new(self.credentials())
+    }
+}
+
+/// Lets one cache be the source of others, e.g. for roles assumed with the proxy's credentials.
+impl CredentialsSource for CredentialsCache {
+    fn load(&self) -> CredentialsFuture {
+        self.credentials()
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    credentials: Option<AwsCredentials>,
+    /// Set while credentials are being loaded, so concurrent requests wait for the same load.
+    refreshing: Option<Shared<CredentialsFuture>>,
+    /// Counts `replace_source` calls, so a load from the old source doesn't land afterwards.
+    generation: usize,
+    /// When the last load failed and why, until a load succeeds.
+    failure: Option<(DateTime<Utc>, String)>,
+}
+
+#[derive(Default)]
+struct RefreshCounters {
+    refreshes: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+/// How often credentials have been loaded, and how often that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RefreshMetrics {
+    pub refreshes: usize,
+    pub failures: usize,
+}
+
+/// Credentials shared by every request, loaded from a `CredentialsSource` when needed.
+#[derive(Clone)]
+pub struct CredentialsCache {
+    source: Arc<RwLock<Arc<dyn CredentialsSource>>>,
+    state: Arc<Mutex<CacheState>>,
+    counters: Arc<RefreshCounters>,
+    retry_interval: Duration,
+}
+
+impl CredentialsCache {
+    pub fn new(source: Box<dyn CredentialsSource>) -> Self {
+        CredentialsCache {
+            source: Arc::new(RwLock::new(Arc::from(source))),
+            state: Arc::new(Mutex::new(CacheState::default())),
+            counters: Arc::new(RefreshCounters::default()),
+            retry_interval: Duration::seconds(MIN_RETRY_INTERVAL),
+        }
+    }
+
+    pub fn metrics(&self) -> RefreshMetrics {
+        RefreshMetrics {
+            refreshes: self.counters.refreshes.load(Ordering::SeqCst),
+            failures: self.counters.failures.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Returns the cached credentials if they are still good, and otherwise waits for new ones.
+    ///
+    /// Must be called from within the hyper runtime, since refreshes that nobody waits for are
+    /// spawned onto it.
+    pub fn credentials(&self) -> CredentialsFuture {
+        let mut state = self.state.lock().unwrap();
+        let remaining = state.credentials.as_ref()
+            .map(|credentials| credentials.expires_at()
+                .map(|expires_at| expires_at.signed_duration_since(Utc::now())));
+        match remaining {
+            // Credentials that never expire, like access keys from the environment.
+            Some(None) => Box::new(future::ok(state.credentials.clone().unwrap())),
+            Some(Some(remaining)) if remaining > Duration::seconds(MIN_REMAINING_LIFETIME) => {
+                if remaining < Duration::seconds(REFRESH_BEFORE_EXPIRY)
+                    && state.refreshing.is_none() && self.recent_failure(&state).is_none() {
+                    let refresh = self.start_refresh(&mut state);
+                    hyper::rt::spawn(refresh.then(|_| Ok(())));
+                }
+                Box::new(future::ok(state.credentials.clone().unwrap()))
+            },
+            _ => {
+                let refresh = match &state.refreshing {
+                    Some(refresh) => refresh.clone(),
+                    None => match self.recent_failure(&state) {
+                        Some(message) => return Box::new(future::err(
+                            CredentialsError::new(message))),
+                        None => self.start_refresh(&mut state),
+                    },
+                };
+                Box::new(refresh
+                    .map(|credentials| (*credentials).clone())
+                    .map_err(|e| CredentialsError::new(e.message.clone())))
+            },
+        }
+    }
+
+    /// Switches to `credentials`, just loaded from `source`, and loads them from `source` from now
+    /// on, e.g. after the configuration changed.  Every clone of this cache gets the new
+    /// credentials.  Loading them first means a source that doesn't work never replaces one that
+    /// does.
+    pub fn replace_source(&self, source: Box<dyn CredentialsSource>,
+        credentials: AwsCredentials) {
+        let mut state = self.state.lock().unwrap();
+        *self.source.write().unwrap() = Arc::from(source);
+        state.credentials = Some(credentials);
+        state.refreshing = None;
+        state.failure = None;
+        state.generation += 1;
+    }
+
+    /// Why the last load failed, if that was too recently to try again.
+    fn recent_failure(&self, state: &CacheState) -> Option<String> {
+        state.failure.as_ref()
+            .filter(|(failed_at, _)| Utc::now().signed_duration_since(*failed_at)
+                < self.retry_interval)
+            .map(|(_, message)| message.clone())
+    }
+
+    /// Starts loading new credentials, which replace the cached ones once they are loaded.
+    fn start_refresh(&self, state: &mut CacheState) -> Shared<CredentialsFuture> {
+        let cache = self.clone();
+        let generation = state.generation;
+        let source = self.source.read().unwrap().clone();
+        let refresh: CredentialsFuture = Box::new(source.load().then(move |result| {
+            let mut state = cache.state.lock().unwrap();
+            if state.generation != generation {
+                return result;
+            }
+            state.refreshing = None;
+            match &result {
+                Ok(credentials) => {
+                    cache.counters.refreshes.fetch_add(1, Ordering::SeqCst);
+                    state.credentials = Some(credentials.clone());
+                    state.failure = None;
+                },
+                Err(e) => {
+                    cache.counters.failures.fetch_add(1, Ordering::SeqCst);
+                    let metrics = cache.metrics();
+                    eprintln!("failed to load AWS credentials ({} of {} attempts failed): {}",
+                        metrics.failures, metrics.failures + metrics.refreshes, e);
+                    state.failure = Some((Utc::now(), e.message.clone()));
+                },
+            }
+            result
+        }));
+        let refresh = refresh.shared();
+        state.refreshing = Some(refresh.clone());
+        refresh
+    }
+}
+
+/// Reads credentials in the JSON format the AWS CLI uses for `credential_process` and its caches,
+/// where only `AccessKeyId` and `SecretAccessKey` are required.
+fn credentials_from_json(json: &serde_json::Value) -> Result<AwsCredentials, CredentialsError> {
+    let field = |name| json[name].as_str().map(String::from);
+    let required = |name| field(name)
+        .ok_or_else(|| CredentialsError::new(format!("no {} in the credentials", name)));
+    let expires_at = match field("Expiration") {
+        Some(expiration) => Some(DateTime::parse_from_rfc3339(&expiration)
+            .map_err(|e| CredentialsError::new(format!("bad expiration {:?}: {}", expiration, e)))?
+            .with_timezone(&Utc)),
+        None => None,
+    };
+    Ok(AwsCredentials::new(required("AccessKeyId")?, required("SecretAccessKey")?,
+        field("SessionToken"), expires_at))
+}
+
+fn credentials_to_json(credentials: &AwsCredentials) -> serde_json::Value {
+    serde_json::json!({
+        "AccessKeyId": credentials.aws_access_key_id(),
+        "SecretAccessKey": credentials.aws_secret_access_key(),
+        "SessionToken": credentials.token(),
+        "Expiration": credentials.expires_at().map(|expires_at| expires_at.to_rfc3339()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use futures::future::{self, Future};
+    use rusoto_credential::{AwsCredentials, CredentialsError};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Hands out credentials that expire `expires_in` from now, counting how often it is asked.
+    struct CountingSource {
+        loads: Arc<AtomicUsize>,
+        expires_in: Option<Duration>,
+    }
+
+    impl super::CredentialsSource for CountingSource {
+        fn load(&self) -> super::CredentialsFuture {
+            let loads = self.loads.fetch_add(1, Ordering::SeqCst) + 1;
+            if loads == 3 {
+                return Box::new(future::err(CredentialsError::new("no credentials")));
+            }
+            Box::new(future::ok(AwsCredentials::new(format!("AKID{}", loads), "secret", None,
+                self.expires_in.map(|expires_in| Utc::now() + expires_in))))
+        }
+    }
+
+    #[test]
+    fn test_credentials_are_cached() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = super::CredentialsCache::new(
+            Box::new(CountingSource { loads: loads.clone(), expires_in: None }));
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID1");
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID1");
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_expired_credentials_are_reloaded() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let mut cache = super::CredentialsCache::new(Box::new(
+            CountingSource { loads: loads.clone(), expires_in: Some(Duration::seconds(30)) }));
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID1");
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID2");
+        // A failed load isn't retried right away.
+        assert!(cache.credentials().wait().is_err());
+        assert!(cache.credentials().wait().is_err());
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+        cache.retry_interval = Duration::zero();
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID4");
+        assert_eq!(cache.metrics(), super::RefreshMetrics { refreshes: 3, failures: 1 });
+    }
+
+    #[test]
+    fn test_replace_source() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = super::CredentialsCache::new(
+            Box::new(CountingSource { loads: loads.clone(), expires_in: None }));
+        let clone = cache.clone();
+        assert_eq!(cache.credentials().wait().unwrap().aws_access_key_id(), "AKID1");
+        let replaced = Arc::new(AtomicUsize::new(10));
+        let source = CountingSource { loads: replaced.clone(), expires_in: None };
+        let credentials = super::CredentialsSource::load(&source).wait().unwrap();
+        cache.replace_source(Box::new(source), credentials);
+        assert_eq!(clone.credentials().wait().unwrap().aws_access_key_id(), "AKID11");
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(replaced.load(Ordering::SeqCst), 11);
+    }
+}
@@ -0,0 +1,96 @@
+//! Credentials printed by an external command, configured with `credential_process` in a profile.
+//!
+//! See https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html
+
+use futures::sync::oneshot;
+use futures::Future;
+use rusoto_credential::{AwsCredentials, CredentialsError};
+
+use std::process::Command;
+use std::thread;
+
+use super::{CredentialsFuture, CredentialsSource};
+
+/// Runs `command` every time credentials are needed.  Credentials without an `Expiration` are
+/// kept for as long as the proxy runs.
+pub struct ProcessSource {
+    command: String,
+}
+
+impl ProcessSource {
+    pub fn new(command: &str) -> Self {
+        ProcessSource { command: command.to_string() }
+    }
+}
+
+impl CredentialsSource for ProcessSource {
+    /// The command may take a while (e.g. to talk to an SSO portal), so it runs on its own thread
+    /// instead of blocking the runtime.
+    fn load(&self) -> CredentialsFuture {
+        let command = self.command.clone();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || sender.send(run(&command)));
+        Box::new(receiver
+            .map_err(|_| CredentialsError::new("credential_process was interrupted"))
+            .and_then(|credentials| credentials))
+    }
+}
+
+fn run(command: &str) -> Result<AwsCredentials, CredentialsError> {
+    let args = shlex::split(command)
+        .filter(|args| !args.is_empty())
+        .ok_or_else(|| CredentialsError::new(format!("bad credential_process {:?}", command)))?;
+    let output = Command::new(&args[0]).args(&args[1..]).output()
+        .map_err(|e| CredentialsError::new(format!("failed to run {:?}: {}", command, e)))?;
+    if !output.status.success() {
+        return Err(CredentialsError::new(format!("{:?} failed with {}: {}", command,
+            output.status, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    parse_output(&output.stdout)
+}
+
+/// Reads the JSON a credential process prints, which has to be `"Version": 1`.
+fn parse_output(output: &[u8]) -> Result<AwsCredentials, CredentialsError> {
+    let output: serde_json::Value = serde_json::from_slice(output)
+        .map_err(|e| CredentialsError::new(format!(
+            "credential_process printed something other than JSON: {}", e)))?;
+    if output["Version"] != 1 {
+        return Err(CredentialsError::new(format!(
+            "credential_process printed Version {}, only 1 is supported", output["Version"])));
+    }
+    super::credentials_from_json(&output)
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_parse_output() {
+        let output = br#"{
+  "Version": 1,
+  "AccessKeyId": "ASIAEXAMPLE",
+  "SecretAccessKey": "secret",
+  "SessionToken": "token",
+  "Expiration": "2019-09-21T03:20:08Z"
+}"#;
+        let credentials = super::parse_output(output).unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "ASIAEXAMPLE");
+        assert_eq!(credentials.token(), &Some(String::from("token")));
+        assert_eq!(credentials.expires_at().unwrap().to_rfc3339(), "2019-09-21T03:20:08+00:00");
+        let output = br#"{"Version": 1, "AccessKeyId": "AKID", "SecretAccessKey": "secret"}"#;
+        assert!(super::parse_output(output).unwrap().expires_at().is_none());
+        assert!(super::parse_output(br#"{"Version": 2}"#).is_err());
+        assert!(super::parse_output(br#"{"Version": 1, "AccessKeyId": "AKID"}"#).is_err());
+        assert!(super::parse_output(b"not json").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run() {
+        let credentials = super::run(
+            r#"echo '{"Version": 1, "AccessKeyId": "AKID", "SecretAccessKey": "secret"}'"#)
+            .unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKID");
+        assert!(super::run("false").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! Named profiles from `~/.aws/credentials` and `~/.aws/config`, the same files the AWS CLI uses.
+//!
+//! A profile either has its own access keys (or a `credential_process` that prints them), or a
+//! `role_arn` to assume with the credentials of its `source_profile` (which may itself be a role,
+//! so roles can be chained), of a `credential_source` like the instance metadata service, or with
+//! a `web_identity_token_file`.
+//!
+//! See https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html
+
+use rusoto_credential::{ContainerProvider, CredentialsError, EnvironmentProvider,
+    InstanceMetadataProvider, StaticProvider};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::mfa::{MfaCodes, MfaRoleSource};
+use super::process::ProcessSource;
+use super::sts::{AssumeRole, AssumeRoleSource, StsClient, WebIdentitySource};
+use super::CredentialsSource;
+
+/// Returns the profile to use: the one given on the command line, or else the one named by
+/// `AWS_PROFILE` (or `AWS_DEFAULT_PROFILE`, which older tools use).
+pub fn selected_profile(profile: Option<String>) -> Option<String> {
+    profile
+        .or_else(|| env::var("AWS_PROFILE").ok())
+        .or_else(|| env::var("AWS_DEFAULT_PROFILE").ok())
+        .filter(|profile| !profile.is_empty())
+}
+
+/// The settings of every profile, with the credentials file taking precedence over the config
+/// file when both set something.
+#[derive(Default)]
+pub struct Profiles {
+    profiles: HashMap<String, HashMap<String, String>>,
+    /// Replaces the STS endpoint that roles are assumed with.
+    sts_endpoint: Option<String>,
+    /// Where codes for roles with an `mfa_serial` come from.
+    mfa_codes: Arc<MfaCodes>,
+}
+
+impl Profiles {
+    /// Reads the files from where `AWS_CONFIG_FILE` and `AWS_SHARED_CREDENTIALS_FILE` say, or
+    /// from `~/.aws`.  Either file may be missing.
+    pub fn load() -> Result<Self, CredentialsError> {
+        let config = read_optional(aws_file("AWS_CONFIG_FILE", "config")?)?;
+        let credentials = read_optional(aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials")?)?;
+        Ok(Profiles::parse(&config, &credentials))
+    }
+
+    /// Assumes roles with the STS at `endpoint` instead of the real one.
+    pub fn sts_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.sts_endpoint = endpoint;
+        self
+    }
+
+    /// Gets the codes for roles with an `mfa_serial` from `mfa_codes`.
+    pub fn mfa_codes(mut self, mfa_codes: Arc<MfaCodes>) -> Self {
+        self.mfa_codes = mfa_codes;
+        self
+    }
+
+    fn parse(config: &str, credentials: &str) -> Self {
+        let mut profiles = Profiles::default();
+        // Profiles in the config file are `[profile name]`, except for `[default]`.
+        for (section, settings) in parse_ini(config) {
+            let name = if section.starts_with("profile ") {
+                section["profile ".len()..].trim().to_string()
+            } else {
+                section
+            };
+            profiles.profiles.entry(name).or_insert_with(HashMap::new).extend(settings);
+        }
+        for (name, settings) in parse_ini(credentials) {
+            profiles.profiles.entry(name).or_insert_with(HashMap::new).extend(settings);
+        }
+        profiles
+    }
+
+    fn get(&self, name: &str) -> Result<&HashMap<String, String>, CredentialsError> {
+        self.profiles.get(name)
+            .ok_or_else(|| CredentialsError::new(format!("profile {} does not exist", name)))
+    }
+
+    /// Returns a setting of a profile, e.g. `region`.
+    #[cfg(test)]
+    fn setting(&self, name: &str, key: &str) -> Option<&str> {
+        self.profiles.get(name)?.get(key).map(String::as_str)
+    }
+
+    /// Whether the credentials of the profile `name` are a role's, so that assuming another role
+    /// with them is role chaining.
+    pub fn assumes_role(&self, name: &str) -> bool {
+        self.profiles.get(name).map_or(false, |profile| profile.contains_key("role_arn"))
+    }
+
+    /// Returns where the credentials for the profile `name` come from.
+    pub fn source(&self, name: &str) -> Result<Box<dyn CredentialsSource>, CredentialsError> {
+        self.source_chain(name, &mut Vec::new())
+    }
+
+    fn source_chain(&self, name: &str, visited: &mut Vec<String>)
+        -> Result<Box<dyn CredentialsSource>, CredentialsError> {
+        if visited.iter().any(|visited| visited == name) {
+            return Err(CredentialsError::new(format!(
+                "profile {} is its own source_profile through {}", name, visited.join(" -> "))));
+        }
+        visited.push(name.to_string());
+        let profile = self.get(name)?;
+        let role_arn = match profile.get("role_arn") {
+            Some(role_arn) => role_arn,
+            None => return own_source(name, profile),
+        };
+        let mut role = AssumeRole::new(role_arn);
+        if let Some(role_session_name) = profile.get("role_session_name") {
+            role.role_session_name = role_session_name.clone();
+        }
+        role.external_id = profile.get("external_id").cloned();
+        if let Some(duration_seconds) = profile.get("duration_seconds") {
+            role.duration_seconds = Some(duration_seconds.parse().map_err(|_| CredentialsError::new(
+                format!("profile {} has a bad duration_seconds {:?}", name, duration_seconds)))?);
+        }
+        let sts = StsClient::new(profile.get("region").map(String::as_str),
+            self.sts_endpoint.as_ref().map(String::as_str))?;
+        if let Some(token_file) = profile.get("web_identity_token_file") {
+            return Ok(Box::new(WebIdentitySource::new(sts, PathBuf::from(token_file), role)));
+        }
+        let (base_name, base) = match (profile.get("source_profile"),
+            profile.get("credential_source")) {
+            // A profile can use its own access keys to assume its role.
+            (Some(source_profile), _) if source_profile == name =>
+                (source_profile, own_source(name, profile)?),
+            (Some(source_profile), _) =>
+                (source_profile, self.source_chain(source_profile, visited)?),
+            (None, Some(credential_source)) =>
+                (credential_source, named_source(credential_source)?),
+            (None, None) => return Err(CredentialsError::new(format!(
+                "profile {} has a role_arn but no source_profile, credential_source or \
+                 web_identity_token_file", name))),
+        };
+        if let Some(mfa_serial) = profile.get("mfa_serial") {
+            role.serial_number = Some(mfa_serial.clone());
+            return Ok(Box::new(MfaRoleSource::new(sts, base, base_name, role,
+                self.mfa_codes.clone())));
+        }
+        Ok(Box::new(AssumeRoleSource::new(sts, base, role)))
+    }
+}
+
+/// The access keys that are right there in the profile, or the command that prints them.
+fn own_source(name: &str, profile: &HashMap<String, String>)
+    -> Result<Box<dyn CredentialsSource>, CredentialsError> {
+    if let Some(command) = profile.get("credential_process") {
+        return Ok(Box::new(ProcessSource::new(command)));
+    }
+    match (profile.get("aws_access_key_id"), profile.get("aws_secret_access_key")) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Box::new(StaticProvider::new(
+            access_key_id.clone(),
+            secret_access_key.clone(),
+            profile.get("aws_session_token").cloned(),
+            None))),
+        _ => Err(CredentialsError::new(format!("profile {} has no credentials", name))),
+    }
+}
+
+/// The sources a `credential_source` setting can name.
+fn named_source(credential_source: &str) -> Result<Box<dyn CredentialsSource>, CredentialsError> {
+    match credential_source {
+        "Environment" => Ok(Box::new(EnvironmentProvider::default())),
+        "Ec2InstanceMetadata" => Ok(Box::new(InstanceMetadataProvider::new())),
+        "EcsContainer" => Ok(Box::new(ContainerProvider::new())),
+        _ => Err(CredentialsError::new(format!("unknown credential_source {:?}",
+            credential_source))),
+    }
+}
+
+fn aws_file(var: &str, name: &str) -> Result<PathBuf, CredentialsError> {
+    match env::var_os(var) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(aws_dir()?.join(name)),
+    }
+}
+
+/// `~/.aws`, where the AWS CLI keeps its files.
+pub(super) fn aws_dir() -> Result<PathBuf, CredentialsError> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+        .ok_or_else(|| CredentialsError::new("can't find the home directory"))?;
+    Ok(PathBuf::from(home).join(".aws"))
+}
+
+fn read_optional(path: PathBuf) -> Result<String, CredentialsError> {
+    if !path.exists() {
+        return Ok(String::new());
+    }
+    fs::read_to_string(&path)
+        .map_err(|e| CredentialsError::new(format!("failed to read {}: {}", path.display(), e)))
+}
+
+/// Parses the sections of an ini file.  Indented lines are nested settings (like the `s3` section
+/// of the config file) that we don't need, so they are skipped.
+fn parse_ini(contents: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut sections: Vec<(String, HashMap<String, String>)> = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with(|c: char| c.is_whitespace()) {
+            continue;
+        }
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].trim().to_string(), HashMap::new()));
+        } else if let (Some(i), Some((_, settings))) = (line.find('='), sections.last_mut()) {
+            settings.insert(line[..i].trim().to_lowercase(), line[i + 1..].trim().to_string());
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+
+    const CONFIG: &str = "
+[default]
+region = us-east-1
+
+[profile dev]
+region = us-west-2
+s3 =
+  max_concurrent_requests = 20
+
+[profile admin]
+role_arn = arn:aws:iam::123456789012:role/admin
+source_profile = dev
+external_id = abc
+
+[profile chained]
+role_arn = arn:aws:iam::123456789012:role/readonly
+source_profile = admin
+
+[profile loop]
+role_arn = arn:aws:iam::123456789012:role/loop
+source_profile = loop2
+
+[profile loop2]
+role_arn = arn:aws:iam::123456789012:role/loop2
+source_profile = loop
+
+[profile nokeys]
+region = eu-west-1
+
+[profile process]
+credential_process = /usr/local/bin/get-credentials --profile \"my profile\"
+
+[profile irsa]
+role_arn = arn:aws:iam::123456789012:role/pod
+web_identity_token_file = /var/run/secrets/eks.amazonaws.com/serviceaccount/token
+
+[profile mfa]
+role_arn = arn:aws:iam::123456789012:role/production
+source_profile = dev
+mfa_serial = arn:aws:iam::123456789012:mfa/me
+
+[profile processrole]
+role_arn = arn:aws:iam::123456789012:role/readonly
+source_profile = process
+";
+
+    const CREDENTIALS: &str = "
+# comment
+[default]
+aws_access_key_id = AKIDDEFAULT
+aws_secret_access_key = defaultsecret
+
+[dev]
+aws_access_key_id=AKIDDEV
+aws_secret_access_key=devsecret
+region = eu-central-1
+";
+
+    #[test]
+    fn test_parse_profiles() {
+        let profiles = super::Profiles::parse(CONFIG, CREDENTIALS);
+        assert_eq!(profiles.setting("default", "region"), Some("us-east-1"));
+        assert_eq!(profiles.setting("default", "aws_access_key_id"), Some("AKIDDEFAULT"));
+        assert_eq!(profiles.setting("dev", "aws_access_key_id"), Some("AKIDDEV"));
+        // The credentials file wins.
+        assert_eq!(profiles.setting("dev", "region"), Some("eu-central-1"));
+        assert_eq!(profiles.setting("dev", "max_concurrent_requests"), None);
+        assert_eq!(profiles.setting("admin", "external_id"), Some("abc"));
+    }
+
+    #[test]
+    fn test_profile_sources() {
+        let profiles = super::Profiles::parse(CONFIG, CREDENTIALS);
+        assert!(profiles.source("dev").is_ok());
+        assert!(!profiles.assumes_role("dev"));
+        assert!(profiles.assumes_role("admin"));
+        assert!(!profiles.assumes_role("missing"));
+        assert!(profiles.source("admin").is_ok());
+        assert!(profiles.source("chained").is_ok());
+        assert!(profiles.source("process").is_ok());
+        assert!(profiles.source("irsa").is_ok());
+        assert!(profiles.source("processrole").is_ok());
+        assert!(profiles.source("mfa").is_ok());
+        assert!(profiles.source("loop").is_err());
+        assert!(profiles.source("nokeys").is_err());
+        assert!(profiles.source("missing").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! A minimal STS client, signed with our own `aws_signature_builder`, for assuming roles and for
+//! finding out who a set of credentials belongs to.  Roles can also be assumed with a web identity
+//! token instead of credentials, like the ones Kubernetes hands out to pods (IRSA).
+//!
+//! See https://docs.aws.amazon.com/STS/latest/APIReference/API_AssumeRole.html and
+//! https://docs.aws.amazon.com/STS/latest/APIReference/API_AssumeRoleWithWebIdentity.html
+
+use chrono::{DateTime, Utc};
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::{Body, Client, Request, Uri};
+use percent_encoding::utf8_percent_encode;
+use rusoto_credential::{AwsCredentials, CredentialsError};
+
+use aws_signature_proxy::aws_signature_builder;
+use aws_signature_builder::endpoints::{self, EndpointResolver, SigningOverride};
+use aws_signature_builder::QUERY_VALUE;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::egress;
+
+use super::{CredentialsFuture, CredentialsSource};
+
+const VERSION: &str = "2011-06-15";
+
+type HttpsClient = Client<egress::HttpsConnector>;
+
+#[derive(Clone)]
+pub struct StsClient {
+    client: HttpsClient,
+    /// Where requests go, e.g. `https://sts.amazonaws.com/`.
+    endpoint: String,
+    /// Signs every request to `endpoint` for STS, even when it isn't an AWS host.
+    resolver: EndpointResolver,
+}
+
+impl StsClient {
+    /// Uses the regional STS endpoint for `region`, or the global one in `us-east-1`.  `endpoint`
+    /// replaces either, e.g. to use a local STS stand-in like `http://localhost:4566`.
+    pub fn new(region: Option<&str>, endpoint: Option<&str>) -> Result<Self, CredentialsError> {
+        let endpoint = match (endpoint, region) {
+            (Some(endpoint), _) => format!("{}/", endpoint.trim_end_matches('/')),
+            (None, Some(region)) => format!("https://sts.{}.{}/", region,
+                endpoints::dns_suffix(region)),
+            (None, None) => String::from("https://sts.amazonaws.com/"),
+        };
+        let host = endpoint.parse::<Uri>().ok()
+            .and_then(|uri| uri.host().map(String::from))
+            .ok_or_else(|| CredentialsError::new(format!("bad STS endpoint {:?}", endpoint)))?;
+        let resolver = EndpointResolver::new(vec![
+            SigningOverride::new(&host, Some("sts"), Some(region.unwrap_or("us-east-1")))]);
+        let https = egress::https_connector(1).map_err(|e| CredentialsError::new(
+            format!("failed to initialize TLS for STS: {}", e)))?;
+        Ok(StsClient { client: Client::builder().build(https), endpoint, resolver })
+    }
+
+    /// Builds the request for `action` with `params`, signed with `credentials` if there are any.
+    fn request(&self, credentials: Option<AwsCredentials>, action: &str,
+        params: &[(String, String)]) -> Result<Request<Body>, CredentialsError> {
+        let mut query = format!("Action={}&Version={}", action, VERSION);
+        for (name, value) in params {
+            query.push_str(&format!("&{}={}", name, utf8_percent_encode(value, QUERY_VALUE)));
+        }
+        let uri = format!("{}?{}", self.endpoint, query);
+        uri.parse::<Uri>()
+            .map_err(|e| CredentialsError::new(format!("bad STS url {}: {}", uri, e)))
+            .and_then(|uri| match credentials {
+                Some(credentials) => sign(credentials, uri, &self.resolver),
+                None => Request::get(uri).body(Body::empty()).map_err(CredentialsError::new),
+            })
+    }
+
+    /// Calls `action` with `params`, signed with `credentials` if there are any, and returns the
+    /// response body.
+    fn call(&self, credentials: Option<AwsCredentials>, action: &str,
+        params: &[(String, String)])
+        -> Box<dyn Future<Item = String, Error = CredentialsError> + Send> {
+        let request = match self.request(credentials, action, params) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let action = action.to_string();
+        Box::new(self.client.request(request)
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            })
+            .map_err(|e| CredentialsError::new(format!("failed to call STS: {}", e)))
+            .and_then(move |(status, body)| {
+                let body = String::from_utf8_lossy(&body).into_owned();
+                if status.is_success() {
+                    Ok(body)
+                } else {
+                    Err(CredentialsError::new(format!("STS {} failed with {}: {} {}", action,
+                        status, xml_text(&body, "Code").unwrap_or_default(),
+                        xml_text(&body, "Message").unwrap_or_default())))
+                }
+            }))
+    }
+
+    /// Returns temporary credentials for the role, using `credentials` to ask for them.
+    pub fn assume_role(&self, credentials: AwsCredentials, role: &AssumeRole)
+        -> CredentialsFuture {
+        Box::new(self.call(Some(credentials), "AssumeRole", &role.params())
+            .and_then(|body| parse_credentials(&body)))
+    }
+
+    /// Returns temporary credentials for the role, using a web identity `token` to ask for them.
+    /// Only the role's ARN, session name and duration are used.
+    pub fn assume_role_with_web_identity(&self, token: &str, role: &AssumeRole)
+        -> CredentialsFuture {
+        let mut params = vec![
+            (String::from("RoleArn"), role.role_arn.clone()),
+            (String::from("RoleSessionName"), role.role_session_name.clone()),
+            (String::from("WebIdentityToken"), token.trim().to_string())];
+        if let Some(duration_seconds) = role.duration_seconds {
+            params.push((String::from("DurationSeconds"), duration_seconds.to_string()));
+        }
+        Box::new(self.call(None, "AssumeRoleWithWebIdentity", &params)
+            .and_then(|body| parse_credentials(&body)))
+    }
+
+    /// Returns the ARN of the user or role that `credentials` belong to.
+    pub fn get_caller_identity(&self, credentials: AwsCredentials)
+        -> Box<dyn Future<Item = String, Error = CredentialsError> + Send> {
+        Box::new(self.call(Some(credentials), "GetCallerIdentity", &[])
+            .and_then(|body| xml_text(&body, "Arn")
+                .ok_or_else(|| CredentialsError::new("GetCallerIdentity returned no Arn"))))
+    }
+}
+
+fn sign(credentials: AwsCredentials, uri: Uri, resolver: &EndpointResolver)
+    -> Result<Request<Body>, CredentialsError> {
+    let mut request = Request::get(uri).body(Body::empty()).map_err(CredentialsError::new)?;
+    aws_signature_builder::generate_aws_signature_headers(
+        aws_signature_builder::AwsUTCDateStrings::new(),
+        credentials,
+        &mut request,
+        aws_signature_builder::Payload::Signed(&[]),
+        &aws_signature_builder::SignedHeadersPolicy::default(),
+        resolver)
+        .and_then(|headers| aws_signature_builder::add_aws_signature_headers(&mut request, headers))
+        .map_err(|e| CredentialsError::new(format!("failed to sign the STS request: {}", e)))?;
+    Ok(request)
+}
+
+/// Reads the `Credentials` of an `AssumeRole` (or `AssumeRoleWithWebIdentity`) response.
+fn parse_credentials(body: &str) -> Result<AwsCredentials, CredentialsError> {
+    let field = |name| xml_text(body, name)
+        .ok_or_else(|| CredentialsError::new(format!("STS response has no {}", name)));
+    let expiration = field("Expiration")?;
+    let expires_at = DateTime::parse_from_rfc3339(&expiration)
+        .map_err(|e| CredentialsError::new(format!("bad expiration {:?}: {}", expiration, e)))?
+        .with_timezone(&Utc);
+    Ok(AwsCredentials::new(field("AccessKeyId")?, field("SecretAccessKey")?,
+        Some(field("SessionToken")?), Some(expires_at)))
+}
+
+/// Returns the text of the first `<tag>` in `xml`.  STS responses are simple enough that this is
+/// all the XML parsing we need.
+fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&"))
+}
+
+/// The parameters of an `AssumeRole` call.
+#[derive(Debug, Clone)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    pub role_session_name: String,
+    pub external_id: Option<String>,
+    /// How long the credentials are valid for.  STS defaults to an hour, which is also the most
+    /// it allows for a role assumed with another role's credentials.
+    pub duration_seconds: Option<u32>,
+    /// Session tags, which show up in CloudTrail and can be used in policies.
+    pub tags: Vec<(String, String)>,
+    /// The MFA device the role requires a code from, see `mfa`.
+    pub serial_number: Option<String>,
+    /// The current code of the MFA device, which is only good for one session.
+    pub token_code: Option<String>,
+}
+
+impl AssumeRole {
+    pub fn new(role_arn: &str) -> Self {
+        AssumeRole {
+            role_arn: role_arn.to_string(),
+            role_session_name: default_session_name(),
+            external_id: None,
+            duration_seconds: None,
+            tags: Vec::new(),
+            serial_number: None,
+            token_code: None,
+        }
+    }
+
+    /// The parameters of the `AssumeRole` call, in the order they are sent.
+    fn params(&self) -> Vec<(String, String)> {
+        let mut params = vec![
+            (String::from("RoleArn"), self.role_arn.clone()),
+            (String::from("RoleSessionName"), self.role_session_name.clone())];
+        if let Some(external_id) = &self.external_id {
+            params.push((String::from("ExternalId"), external_id.clone()));
+        }
+        if let Some(duration_seconds) = self.duration_seconds {
+            params.push((String::from("DurationSeconds"), duration_seconds.to_string()));
+        }
+        if let (Some(serial_number), Some(token_code)) = (&self.serial_number, &self.token_code) {
+            params.push((String::from("SerialNumber"), serial_number.clone()));
+            params.push((String::from("TokenCode"), token_code.clone()));
+        }
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            params.push((format!("Tags.member.{}.Key", i + 1), key.clone()));
+            params.push((format!("Tags.member.{}.Value", i + 1), value.clone()));
+        }
+        params
+    }
+}
+
+/// Sessions are named after the proxy, so they are easy to find in CloudTrail.
+fn default_session_name() -> String {
+    format!("aws-signature-proxy-{}", Utc::now().timestamp())
+}
+
+/// Credentials for a role, assumed with the credentials from `base`.
+pub struct AssumeRoleSource {
+    sts: StsClient,
+    base: Box<dyn CredentialsSource>,
+    role: AssumeRole,
+}
+
+impl AssumeRoleSource {
+    pub fn new(sts: StsClient, base: Box<dyn CredentialsSource>, role: AssumeRole) -> Self {
+        AssumeRoleSource { sts, base, role }
+    }
+}
+
+impl CredentialsSource for AssumeRoleSource {
+    fn load(&self) -> CredentialsFuture {
+        let sts = self.sts.clone();
+        let role = self.role.clone();
+        Box::new(self.base.load()
+            .and_then(move |credentials| sts.assume_role(credentials, &role)))
+    }
+}
+
+/// Credentials for a role, assumed with the web identity token in `token_file`.  The file is read
+/// again every time, since whoever put it there replaces it before it expires.
+pub struct WebIdentitySource {
+    sts: StsClient,
+    token_file: PathBuf,
+    role: AssumeRole,
+}
+
+impl WebIdentitySource {
+    pub fn new(sts: StsClient, token_file: PathBuf, role: AssumeRole) -> Self {
+        WebIdentitySource { sts, token_file, role }
+    }
+
+    /// Uses `AWS_WEB_IDENTITY_TOKEN_FILE`, `AWS_ROLE_ARN` and `AWS_ROLE_SESSION_NAME`, which is how
+    /// EKS tells pods which role to use.  Returns `None` if they aren't set.
+    pub fn from_env(sts_endpoint: Option<&str>) -> Option<Result<Self, CredentialsError>> {
+        let token_file = env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE")?;
+        let role_arn = env::var("AWS_ROLE_ARN").ok()?;
+        let mut role = AssumeRole::new(&role_arn);
+        if let Ok(role_session_name) = env::var("AWS_ROLE_SESSION_NAME") {
+            role.role_session_name = role_session_name;
+        }
+        let region = env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")).ok();
+        Some(StsClient::new(region.as_ref().map(String::as_str), sts_endpoint)
+            .map(|sts| WebIdentitySource::new(sts, PathBuf::from(token_file), role)))
+    }
+}
+
+impl CredentialsSource for WebIdentitySource {
+    fn load(&self) -> CredentialsFuture {
+        match fs::read_to_string(&self.token_file) {
+            Ok(token) => self.sts.assume_role_with_web_identity(&token, &self.role),
+            Err(e) => Box::new(future::err(CredentialsError::new(format!(
+                "failed to read the web identity token {}: {}", self.token_file.display(), e)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_parse_credentials() {
+        let body = r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleResult>
+    <Credentials>
+      <SessionToken>FwoGZXIvYXdzEBYaDK+token=</SessionToken>
+      <SecretAccessKey>wJalrXUtnFEMI/K7MDENG/bPxRfiCYzEXAMPLEKEY</SecretAccessKey>
+      <Expiration>2019-09-21T03:20:08Z</Expiration>
+      <AccessKeyId>ASIAJEXAMPLEXEG2JICEA</AccessKeyId>
+    </Credentials>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::123456789012:assumed-role/demo/session</Arn>
+    </AssumedRoleUser>
+  </AssumeRoleResult>
+</AssumeRoleResponse>"#;
+        let credentials = super::parse_credentials(body).unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "ASIAJEXAMPLEXEG2JICEA");
+        assert_eq!(credentials.aws_secret_access_key(),
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYzEXAMPLEKEY");
+        assert_eq!(credentials.token(), &Some(String::from("FwoGZXIvYXdzEBYaDK+token=")));
+        assert_eq!(credentials.expires_at().unwrap().to_rfc3339(), "2019-09-21T03:20:08+00:00");
+        assert!(super::parse_credentials("<Error><Code>AccessDenied</Code></Error>").is_err());
+    }
+
+    /// The query is built the same way for any endpoint, and a stand-in STS is signed for as if it
+    /// were AWS.
+    #[test]
+    fn test_assume_role_request() {
+        let sts = super::StsClient::new(None, Some("http://localhost:4566/")).unwrap();
+        let mut role = super::AssumeRole::new("arn:aws:iam::123456789012:role/demo");
+        role.role_session_name = String::from("session");
+        role.external_id = Some(String::from("a b+c"));
+        role.duration_seconds = Some(900);
+        role.tags = vec![
+            (String::from("team"), String::from("payments")),
+            (String::from("env"), String::from("dev")),
+        ];
+        let credentials = rusoto_credential::AwsCredentials::new("AKIDEXAMPLE", "secret", None,
+            None);
+        let request = sts.request(Some(credentials), "AssumeRole", &role.params()).unwrap();
+        assert_eq!(request.uri(), "http://localhost:4566/?Action=AssumeRole&Version=2011-06-15&\
+            RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fdemo&RoleSessionName=session&\
+            ExternalId=a%20b%2Bc&DurationSeconds=900&\
+            Tags.member.1.Key=team&Tags.member.1.Value=payments&\
+            Tags.member.2.Key=env&Tags.member.2.Value=dev");
+        let authorization = request.headers()["authorization"].to_str().unwrap();
+        assert!(authorization.contains("/us-east-1/sts/aws4_request"), "{}", authorization);
+    }
+
+    #[test]
+    fn test_regional_endpoint() {
+        let endpoint = |region| super::StsClient::new(region, None).unwrap().endpoint;
+        assert_eq!(endpoint(None), "https://sts.amazonaws.com/");
+        assert_eq!(endpoint(Some("eu-west-1")), "https://sts.eu-west-1.amazonaws.com/");
+        assert_eq!(endpoint(Some("cn-north-1")), "https://sts.cn-north-1.amazonaws.com.cn/");
+    }
+
+    #[test]
+    fn test_xml_text() {
+        let body = "<Error><Code>AccessDenied</Code><Message>a &amp; b</Message></Error>";
+        assert_eq!(super::xml_text(body, "Code"), Some(String::from("AccessDenied")));
+        assert_eq!(super::xml_text(body, "Message"), Some(String::from("a & b")));
+        assert_eq!(super::xml_text(body, "Arn"), None);
+    }
+}
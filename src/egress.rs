@@ -0,0 +1,288 @@
+//! How the proxy's own connections to AWS (and to anywhere else it forwards to) are made: directly,
+//! or through an upstream HTTP proxy for networks that only let traffic out that way.
+//!
+//! Connections through the upstream proxy are always `CONNECT` tunnels, even for plain http, so
+//! requests keep the exact form they were signed in.  Like curl and the AWS CLI, hosts listed in
+//! `NO_PROXY` are connected to directly.
+
+use futures::future::{self, Future, Loop};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// The most we read of the upstream proxy's answer to a `CONNECT` before giving up on it.
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+lazy_static! {
+    static ref UPSTREAM_PROXY: Mutex<Option<Arc<UpstreamProxy>>> = Mutex::new(None);
+}
+
+/// Makes every `Connector` created from now on go through `proxy`.
+pub fn set_upstream_proxy(proxy: Option<UpstreamProxy>) {
+    *UPSTREAM_PROXY.lock().unwrap() = proxy.map(Arc::new);
+}
+
+/// A client connector that makes TLS connections for https, through the upstream proxy if there
+/// is one.
+pub type HttpsConnector = hyper_tls::HttpsConnector<Connector>;
+
+pub fn https_connector(threads: usize) -> Result<HttpsConnector, native_tls::Error> {
+    let tls = native_tls::TlsConnector::new()?;
+    Ok(hyper_tls::HttpsConnector::from((Connector::new(threads), tls)))
+}
+
+/// An HTTP proxy to tunnel through, as http://[USER:PASSWORD@]HOST[:PORT].
+#[derive(Debug, Clone)]
+pub struct UpstreamProxy {
+    /// The proxy's address, without the credentials.
+    uri: Uri,
+    /// The `Proxy-Authorization` to send with every `CONNECT`.
+    authorization: Option<String>,
+    /// Hosts that are connected to directly, see `bypasses`.
+    no_proxy: Vec<String>,
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = match s.find("://") {
+            Some(i) if &s[..i] == "http" => &s[i + 3..],
+            Some(_) => return Err(format!("only http:// proxies are supported, got {:?}", s)),
+            None => s,
+        };
+        let rest = rest.trim_end_matches('/');
+        let (userinfo, address) = match rest.rfind('@') {
+            Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+            None => (None, rest),
+        };
+        let uri = format!("http://{}", address).parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.host().map_or(false, |host| !host.is_empty()))
+            .ok_or_else(|| format!("expected http://[USER:PASSWORD@]HOST[:PORT], got {:?}", s))?;
+        let authorization = match userinfo {
+            Some(userinfo) => {
+                let userinfo = percent_decode_str(userinfo).decode_utf8()
+                    .map_err(|_| format!("the credentials in {:?} aren't UTF-8", s))?;
+                Some(format!("Basic {}", openssl::base64::encode_block(userinfo.as_bytes())))
+            },
+            None => None,
+        };
+        Ok(UpstreamProxy { uri, authorization, no_proxy: Vec::new() })
+    }
+}
+
+impl fmt::Display for UpstreamProxy {
+    /// Never shows the credentials.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.uri)
+    }
+}
+
+impl UpstreamProxy {
+    /// The proxy from `HTTPS_PROXY` (or `https_proxy`), if it is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match env_var(&["HTTPS_PROXY", "https_proxy"]) {
+            Some(proxy) => proxy.parse().map(Some)
+                .map_err(|e| format!("bad HTTPS_PROXY: {}", e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Connects directly to the hosts in `no_proxy`, a comma separated list like `NO_PROXY`.
+    pub fn no_proxy(mut self, no_proxy: &str) -> Self {
+        self.no_proxy = no_proxy.split(',')
+            .map(|host| host.trim().trim_start_matches('.').to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        self
+    }
+
+    /// Connects directly to the hosts in `NO_PROXY` (or `no_proxy`).
+    pub fn no_proxy_from_env(self) -> Self {
+        match env_var(&["NO_PROXY", "no_proxy"]) {
+            Some(no_proxy) => self.no_proxy(&no_proxy),
+            None => self,
+        }
+    }
+
+    /// Whether `host` is connected to directly.  `*` matches every host, and anything else
+    /// matches itself and its subdomains.
+    fn bypasses(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        self.no_proxy.iter().any(|pattern| {
+            pattern == "*" || host == *pattern ||
+                (host.ends_with(pattern.as_str()) &&
+                    host[..host.len() - pattern.len()].ends_with('.'))
+        })
+    }
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Makes TCP connections, through the upstream proxy that was set when it was created.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    proxy: Option<Arc<UpstreamProxy>>,
+}
+
+impl Connector {
+    pub fn new(threads: usize) -> Self {
+        let mut http = HttpConnector::new(threads);
+        http.enforce_http(false);
+        Connector { http, proxy: UPSTREAM_PROXY.lock().unwrap().clone() }
+    }
+
+    /// Connects to `host` on `port`, either directly or with a tunnel through the upstream proxy.
+    pub fn connect_tcp(&self, host: &str, port: u16)
+        -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+        let target = if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let proxy = self.proxy.clone().filter(|proxy| !proxy.bypasses(host));
+        let uri = match &proxy {
+            Some(proxy) => Ok(proxy.uri.clone()),
+            None => format!("http://{}", target).parse::<Uri>()
+                .map_err(|e| other_error(format!("bad address {}: {}", target, e))),
+        };
+        let destination = match uri.and_then(|uri| Destination::try_from_uri(uri)
+            .map_err(|e| other_error(format!("bad address {}: {}", target, e)))) {
+            Ok(destination) => destination,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let connecting = self.http.connect(destination).map(|(stream, _)| stream);
+        match proxy {
+            Some(proxy) => {
+                let address = proxy.to_string();
+                Box::new(connecting
+                    .map_err(move |e| other_error(format!(
+                        "can't connect to the upstream proxy {}: {}", address, e)))
+                    .and_then(move |stream| tunnel(stream, target, proxy)))
+            },
+            None => Box::new(connecting),
+        }
+    }
+}
+
+impl Connect for Connector {
+    type Transport = TcpStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let port = dst.port().unwrap_or(if dst.scheme() == "https" { 443 } else { 80 });
+        Box::new(self.connect_tcp(dst.host(), port).map(|stream| (stream, Connected::new())))
+    }
+}
+
+/// Asks the upstream proxy on `stream` to connect it to `target`.
+fn tunnel(stream: TcpStream, target: String, proxy: Arc<UpstreamProxy>)
+    -> impl Future<Item = TcpStream, Error = io::Error> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(authorization) = &proxy.authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+    write_all(stream, request.into_bytes())
+        .and_then(|(stream, _)| read_response_head(stream))
+        .and_then(move |(stream, head)| match check_response(&head) {
+            Ok(()) => Ok(stream),
+            Err(e) => Err(other_error(format!("the upstream proxy {} won't connect to {}: {}",
+                proxy, target, e))),
+        })
+}
+
+/// Reads one byte at a time, since whatever follows the head already belongs to the tunnel.
+fn read_response_head(stream: TcpStream)
+    -> impl Future<Item = (TcpStream, Vec<u8>), Error = io::Error> {
+    future::loop_fn((stream, Vec::new()), |(stream, mut head)| {
+        read_exact(stream, [0; 1]).and_then(|(stream, byte)| {
+            head.push(byte[0]);
+            if head.ends_with(b"\r\n\r\n") {
+                Ok(Loop::Break((stream, head)))
+            } else if head.len() > MAX_RESPONSE_HEAD {
+                Err(other_error(String::from("the upstream proxy's response is too long")))
+            } else {
+                Ok(Loop::Continue((stream, head)))
+            }
+        })
+    })
+}
+
+/// Any 2xx means the tunnel is open.
+fn check_response(head: &[u8]) -> Result<(), String> {
+    let status_line = String::from_utf8_lossy(head).lines().next().unwrap_or("").to_string();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') && status.len() == 3 => Ok(()),
+        Some("407") => Err(format!("{} (check the credentials of --upstream-proxy)",
+            status_line)),
+        _ => Err(status_line),
+    }
+}
+
+fn other_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpstreamProxy;
+
+    #[test]
+    fn test_parse_upstream_proxy() {
+        let proxy: UpstreamProxy = "http://proxy.example.com:3128".parse().unwrap();
+        assert_eq!(proxy.uri.host(), Some("proxy.example.com"));
+        assert_eq!(proxy.uri.port_u16(), Some(3128));
+        assert_eq!(proxy.authorization, None);
+        let proxy: UpstreamProxy = "user:p%40ss@proxy:8080/".parse().unwrap();
+        assert_eq!(proxy.uri.host(), Some("proxy"));
+        assert_eq!(proxy.authorization, Some(String::from("Basic dXNlcjpwQHNz")));
+        assert!(!proxy.to_string().contains("user"));
+        assert!("https://proxy:3128".parse::<UpstreamProxy>().is_err());
+        assert!("socks5://proxy:1080".parse::<UpstreamProxy>().is_err());
+        assert!("http://".parse::<UpstreamProxy>().is_err());
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let proxy = "proxy:3128".parse::<UpstreamProxy>().unwrap()
+            .no_proxy("localhost, .internal.example.com,127.0.0.1,::1");
+        assert!(proxy.bypasses("localhost"));
+        assert!(proxy.bypasses("internal.example.com"));
+        assert!(proxy.bypasses("s3.Internal.Example.com"));
+        assert!(proxy.bypasses("127.0.0.1"));
+        assert!(proxy.bypasses("[::1]"));
+        assert!(!proxy.bypasses("notinternal.example.com"));
+        assert!(!proxy.bypasses("sts.amazonaws.com"));
+        let proxy = proxy.no_proxy("*");
+        assert!(proxy.bypasses("sts.amazonaws.com"));
+    }
+
+    #[test]
+    fn test_check_response() {
+        assert!(super::check_response(b"HTTP/1.1 200 Connection established\r\n\r\n").is_ok());
+        assert!(super::check_response(b"HTTP/1.0 200 OK\r\nVia: proxy\r\n\r\n").is_ok());
+        let e = super::check_response(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap_err();
+        assert!(e.contains("--upstream-proxy"));
+        assert!(super::check_response(b"HTTP/1.1 403 Forbidden\r\n\r\n").is_err());
+        assert!(super::check_response(b"garbage\r\n\r\n").is_err());
+    }
+}
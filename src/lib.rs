@@ -0,0 +1,4 @@
+//! The request signing used by the proxy, also usable on its own, e.g. to verify signed requests
+//! in a service that mimics an AWS API (see `aws_signature_builder::verify`).
+
+pub mod aws_signature_builder;
@@ -0,0 +1,122 @@
+//! The addresses the proxy listens on: TCP (IPv4 or IPv6) or Unix domain sockets.
+//!
+//! Anyone who can connect to a listener can sign requests with the proxy's credentials, so TCP
+//! listeners default to the loopback address, and Unix sockets are only usable by their owner
+//! unless told otherwise.
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Somewhere to listen, as PORT (on 127.0.0.1), ADDRESS:PORT (e.g. 0.0.0.0:8080 or [::1]:8080)
+/// or unix:PATH.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// `port` on 127.0.0.1, which is what the proxy has always listened on.
+    pub fn local(port: u16) -> Self {
+        Listen::Tcp((Ipv4Addr::LOCALHOST, port).into())
+    }
+
+    /// Whether only this machine can connect.
+    pub fn is_local(&self) -> bool {
+        match self {
+            Listen::Tcp(addr) => addr.ip().is_loopback(),
+            Listen::Unix(_) => true,
+        }
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err(String::from("expected unix:PATH"));
+            }
+            if cfg!(not(unix)) {
+                return Err(format!("Unix sockets aren't supported here, got {:?}", s));
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(Listen::local(port));
+        }
+        s.parse::<SocketAddr>()
+            .map(Listen::Tcp)
+            .map_err(|_| format!("expected PORT, ADDRESS:PORT or unix:PATH, got {:?}", s))
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "http://{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses a file mode in octal, like chmod.
+pub fn parse_socket_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("expected an octal file mode like 660, got {:?}", mode))
+}
+
+/// Binds a Unix socket at `path` that can be used as `mode` allows.  A socket left behind by an
+/// earlier run is replaced, but nothing else is.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path, mode: u32) -> std::io::Result<tokio::net::UnixListener> {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()))),
+        Err(_) => (),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listen;
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!("8080".parse::<Listen>().unwrap(), Listen::local(8080));
+        assert_eq!("0.0.0.0:8080".parse::<Listen>().unwrap(),
+            Listen::Tcp(([0, 0, 0, 0], 8080).into()));
+        let ipv6 = "[::1]:8080".parse::<Listen>().unwrap();
+        assert_eq!(ipv6, Listen::Tcp("[::1]:8080".parse().unwrap()));
+        assert!(ipv6.is_local());
+        assert!(!"[::]:8080".parse::<Listen>().unwrap().is_local());
+        assert!(Listen::local(8080).is_local());
+        assert!("localhost:8080".parse::<Listen>().is_err());
+        assert!("unix:".parse::<Listen>().is_err());
+        assert_eq!(super::parse_socket_mode("660"), Ok(0o660));
+        assert!(super::parse_socket_mode("999").is_err());
+        assert!(super::parse_socket_mode("7777").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_unix_listen() {
+        let listen = "unix:/run/aws-signature-proxy.sock".parse::<Listen>().unwrap();
+        assert_eq!(listen, Listen::Unix("/run/aws-signature-proxy.sock".into()));
+        assert!(listen.is_local());
+        assert_eq!(listen.to_string(), "unix:/run/aws-signature-proxy.sock");
+    }
+}
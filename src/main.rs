@@ -1,47 +1,423 @@
 extern crate simple_proxy;
 
-mod aws_signature_builder;
+mod config;
+mod credentials;
+mod egress;
+mod listen;
+mod metadata;
+mod mitm;
+mod routes;
+mod signals;
+
+use aws_signature_proxy::aws_signature_builder;
 
 use structopt::StructOpt;
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Clone)]
 struct Cli {
-    port: u16,
+    /// The port to listen on when running the proxy, on 127.0.0.1.
+    port: Option<u16>,
+    /// Also listen on this address, as PORT (on 127.0.0.1), ADDRESS:PORT (e.g. 0.0.0.0:8080 or
+    /// [::1]:8080) or unix:PATH.  Every listener takes both proxy and reverse proxy requests.  Can
+    /// be given more than once.
+    #[structopt(long = "listen", raw(number_of_values = "1"))]
+    listen: Vec<listen::Listen>,
+    /// The file mode of unix:PATH listeners, in octal.  Only those who can write to the socket can
+    /// use the proxy's credentials.  Defaults to 600.
+    #[structopt(long = "socket-mode", parse(try_from_str = "listen::parse_socket_mode"))]
+    socket_mode: Option<u32>,
+    /// Sign requests to this service (e.g. "s3") with `UNSIGNED-PAYLOAD`, so request bodies are
+    /// streamed through without being hashed.  Can be given more than once.
+    #[structopt(long = "unsigned-payload", raw(number_of_values = "1"))]
+    unsigned_payload: Vec<String>,
+    /// Sign this request header.  A trailing "*" matches any header with that prefix.  Can be
+    /// given more than once, and replaces the default of "content-md5", "content-type" and
+    /// "x-amz-*".
+    #[structopt(long = "sign-header", raw(number_of_values = "1"))]
+    sign_header: Vec<String>,
+    /// Sign requests to hosts matching a pattern for a different service and/or region, as
+    /// HOST_PATTERN=SERVICE:REGION, e.g. "api.example.com=execute-api:us-west-2" for an API Gateway
+    /// custom domain or "*.example.com=:eu-west-1".  A leading "*" in the pattern matches any
+    /// prefix.  The first matching pattern wins.  Can be given more than once.
+    #[structopt(long = "signing-override", raw(number_of_values = "1"))]
+    signing_override: Vec<aws_signature_builder::endpoints::SigningOverride>,
+    /// Only sign requests to hosts matching this pattern, where a leading "*" matches any prefix.
+    /// Can be given more than once, and replaces the default of "*.amazonaws.com",
+    /// "*.amazonaws.com.cn" and "*.api.aws".  Hosts with a --signing-override are always signed.
+    #[structopt(long = "allow-host", raw(number_of_values = "1"))]
+    allow_host: Vec<String>,
+    /// Refuse requests to hosts that aren't allowed, instead of passing them through untouched.
+    #[structopt(long = "reject-other-hosts")]
+    reject_other_hosts: bool,
+    /// Pass requests to hosts that aren't allowed through, even if the --config file says to
+    /// refuse them.
+    #[structopt(long = "no-reject-other-hosts", raw(conflicts_with = "\"reject_other_hosts\""))]
+    no_reject_other_hosts: bool,
+    /// Also work as a plain HTTP server, which sends requests for /SERVICE/REGION/PATH to
+    /// https://SERVICE.REGION.amazonaws.com/PATH, for clients that can't use a proxy.
+    #[structopt(long = "reverse-proxy")]
+    reverse_proxy: bool,
+    /// Don't work as a plain HTTP server, even if the --config file says to.
+    #[structopt(long = "no-reverse-proxy", raw(conflicts_with = "\"reverse_proxy\""))]
+    no_reverse_proxy: bool,
+    /// Send requests for /NAME/PATH to BASE_URL/PATH, as NAME=BASE_URL.  Works without
+    /// --reverse-proxy, and wins over it.  Can be given more than once.
+    #[structopt(long = "route", raw(number_of_values = "1"))]
+    route: Vec<routes::Route>,
+    /// Send requests to hosts matching a pattern to another server, as HOST_PATTERN=URL, e.g.
+    /// "*.amazonaws.com=http://localhost:4566" for LocalStack.  They are still signed for the
+    /// region and service of the original host, which is kept as the Host header.  The first
+    /// matching pattern wins.  Can be given more than once.
+    #[structopt(long = "upstream", raw(number_of_values = "1"))]
+    upstream: Vec<routes::Upstream>,
+    /// Sign with the credentials of this profile from ~/.aws/credentials and ~/.aws/config,
+    /// including roles it assumes with a source_profile.  Defaults to $AWS_PROFILE, and without
+    /// either the usual environment variables, default profile and instance credentials are used.
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+    /// Sign with the credentials of this role, assumed with the profile's (or default)
+    /// credentials.  Given more than once, each role is assumed with the credentials of the one
+    /// before it.  The credentials are assumed again before they expire.
+    #[structopt(long = "role-arn", raw(number_of_values = "1"))]
+    role_arn: Vec<String>,
+    /// The external ID to assume --role-arn with, if its trust policy requires one.
+    #[structopt(long = "external-id")]
+    external_id: Option<String>,
+    /// The session name to assume --role-arn with, which shows up in CloudTrail.
+    #[structopt(long = "role-session-name")]
+    role_session_name: Option<String>,
+    /// How many seconds the --role-arn credentials are valid for, between 900 and 43200.  Roles
+    /// assumed with another role's credentials are limited to 3600, so with more than one
+    /// --role-arn it can't be more than that.
+    #[structopt(long = "role-duration-seconds")]
+    role_duration_seconds: Option<u32>,
+    /// A session tag to assume --role-arn with, as KEY=VALUE.  Can be given more than once.
+    #[structopt(long = "role-tag", raw(number_of_values = "1"),
+        parse(try_from_str = "parse_tag"))]
+    role_tag: Vec<(String, String)>,
+    /// Assume roles with the STS at this url instead of AWS, e.g. http://localhost:4566.
+    #[structopt(long = "sts-endpoint")]
+    sts_endpoint: Option<String>,
+    /// Make every connection through this HTTP proxy, as http://[USER:PASSWORD@]HOST[:PORT], for
+    /// networks that only let traffic out that way.  Defaults to $HTTPS_PROXY.  Hosts in
+    /// $NO_PROXY are always connected to directly.
+    #[structopt(long = "upstream-proxy")]
+    upstream_proxy: Option<egress::UpstreamProxy>,
+    /// The ARN (or serial number) of the MFA device the first --role-arn has to be assumed with.
+    #[structopt(long = "mfa-serial")]
+    mfa_serial: Option<String>,
+    /// The current code of the MFA device, for the first role that needs one.  Codes for later
+    /// sessions are asked for on the terminal.
+    #[structopt(long = "mfa-token")]
+    mfa_token: Option<String>,
+    /// Let clients sign as someone else by sending NAME:TOKEN as their proxy credentials (or in an
+    /// X-Proxy-Profile header).  As NAME:TOKEN they get the credentials of the profile NAME, and as
+    /// NAME:TOKEN=ROLE_ARN those of the role, assumed with the proxy's own credentials.  Once any
+    /// are given, every request has to pick one.  Can be given more than once.
+    #[structopt(long = "client", raw(number_of_values = "1"))]
+    client: Vec<credentials::clients::ClientSpec>,
+    /// Also hand out the credentials requests are signed with on this port, as an EC2 instance
+    /// metadata service (IMDSv2) and as an ECS container credentials endpoint, for tools that
+    /// can't use a proxy.
+    #[structopt(long = "metadata-port")]
+    metadata_port: Option<u16>,
+    /// The Authorization header the container credentials endpoint expects.  Defaults to a random
+    /// token that is printed at startup.
+    #[structopt(long = "container-authorization-token")]
+    container_authorization_token: Option<String>,
+    /// Where the CA made by `ca init` is kept.  Defaults to ~/.aws-signature-proxy.
+    #[structopt(long = "ca-dir", parse(from_os_str))]
+    ca_dir: Option<PathBuf>,
+    /// Don't print a line for every request.
+    #[structopt(long = "quiet")]
+    quiet: bool,
+    /// Print a line for every request, even if the --config file says to be quiet.
+    #[structopt(long = "no-quiet", raw(conflicts_with = "\"quiet\""))]
+    no_quiet: bool,
+    /// How many seconds to wait for open connections to finish after SIGTERM, before exiting
+    /// anyway.  Defaults to 30.
+    #[structopt(long = "shutdown-timeout")]
+    shutdown_timeout: Option<u64>,
+    /// Read settings from this TOML file, where every option is set under its name with
+    /// underscores, e.g. `allow_host = ["*.amazonaws.com"]`.  Options on the command line win.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    match tag.find('=') {
+        Some(i) => Ok((tag[..i].to_string(), tag[i + 1..].to_string())),
+        None => Err(format!("expected KEY=VALUE, got {:?}", tag)),
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Prints a presigned url, which lets anyone make that one request until it expires.
+    #[structopt(name = "presign")]
+    Presign {
+        /// The url to presign, e.g. https://s3.amazonaws.com/examplebucket/test.txt
+        url: Uri,
+        /// The HTTP method the url will be used with.
+        #[structopt(long = "method", default_value = "GET")]
+        method: String,
+        /// How many seconds the url is valid for, at most 604800 (seven days).
+        #[structopt(long = "expires-in", default_value = "3600")]
+        expires_in: u64,
+    },
+    /// Manages the CA that signs the certificates the proxy presents to clients.
+    #[structopt(name = "ca")]
+    Ca {
+        #[structopt(subcommand)]
+        command: CaCommand,
+    },
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum CaCommand {
+    /// Creates the CA.  Add its certificate (see `ca export`) to your trust store, and every host
+    /// the proxy intercepts will be trusted.
+    #[structopt(name = "init")]
+    Init {
+        /// Replace an existing CA, which has to be trusted all over again.
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// Prints the CA certificate as PEM.
+    #[structopt(name = "export")]
+    Export,
 }
 
-use rusoto_credential::{ProvideAwsCredentials, DefaultCredentialsProvider};
+use rusoto_credential::{AwsCredentials, ChainProvider, CredentialsError};
+use futures::future;
 use futures::future::Future;
+use futures::Stream;
 
-use hyper::{Body, Chunk, Request, Response, Server};
+use hyper::header::{HeaderValue, HOST, PROXY_AUTHENTICATE};
+use hyper::{Body, Chunk, Request, Response, Server, StatusCode};
 
 use http::uri::Uri;
 
-use monie::{Mitm, MitmProxyService};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use aws_signature_builder::SigningError;
+use credentials::clients::Clients;
+use credentials::mfa::MfaCodes;
+use credentials::{CredentialsCache, CredentialsSource};
+use mitm::certs::CertificateAuthority;
+use mitm::{HostPolicy, Mitm, MitmProxyService, RequestFuture};
+
+/// Settings from the command line that apply to every request.
+struct ProxySettings {
+    /// The credentials requests are signed with, unless the client picked one of `clients`.
+    credentials: CredentialsCache,
+    /// The identities clients can pick.
+    clients: Clients,
+    /// Services whose requests are signed with `UNSIGNED-PAYLOAD`.
+    unsigned_payload_services: Vec<String>,
+    /// Which request headers are included in the signature.
+    signed_headers: aws_signature_builder::SignedHeadersPolicy,
+    /// Which region and service each host is signed for.
+    resolver: aws_signature_builder::endpoints::EndpointResolver,
+    /// The hosts requests are signed for, since credentials must not be sent anywhere else.
+    allowed_hosts: aws_signature_builder::endpoints::HostAllowlist,
+    /// What happens to requests to other hosts.
+    other_hosts: HostPolicy,
+    /// Where requests made to the proxy itself go.
+    routes: routes::Routes,
+    /// Where requests to some hosts really go.
+    upstreams: routes::Upstreams,
+    /// Whether to keep quiet about every request.
+    quiet: bool,
+}
+
+impl ProxySettings {
+    /// Whether the body of `req` should be left out of the signature.  AWS only accepts this over
+    /// TLS.
+    fn uses_unsigned_payload(&self, req: &Request<Body>) -> bool {
+        req.uri().scheme_str() == Some("https") &&
+            aws_signature_builder::service_name(req, &self.resolver)
+                .map_or(false, |service| self.unsigned_payload_services.contains(&service))
+    }
+}
+
+struct AddsAWSSignatureHeaders {
+    settings: Arc<ProxySettings>,
+    /// Picks one of `settings.clients`.
+    proxy_authorization: Option<HeaderValue>,
+    /// Set when the request body is being streamed as an `aws-chunked` upload instead of being
+    /// buffered.
+    chunked_encoder: Arc<Mutex<Option<aws_signature_builder::chunked::ChunkedEncoder>>>,
+}
 
-#[derive(Debug)]
-struct AddsAWSSignatureHeaders;
+/// The response to send back when a request can't be signed.  Missing credentials are our
+/// problem, but a request we can't make sense of is the client's.
+fn signing_error_response(e: SigningError) -> Response<Body> {
+    let status = match e {
+        SigningError::MissingHost(_) | SigningError::InvalidHeaderValue { .. } =>
+            StatusCode::BAD_REQUEST,
+        SigningError::Credentials(_) => StatusCode::SERVICE_UNAVAILABLE,
+        SigningError::Crypto(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    mitm::error_response(status, e.to_string())
+}
 
-fn add_signature_headers(req: Request<Body>) -> Request<Body> {
-    let mut request = Request::from(req);
+/// The response to send back when the client didn't say who it is, or got its token wrong.
+fn proxy_authentication_required(e: CredentialsError) -> Response<Body> {
+    let mut res = mitm::error_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED, e.message);
+    res.headers_mut().insert(PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"aws-signature-proxy\""));
+    res
+}
+
+fn add_signature_headers(
+    mut request: Request<Body>,
+    payload: aws_signature_builder::Payload,
+    credentials: AwsCredentials,
+    settings: &ProxySettings) -> Result<Request<Body>, SigningError> {
     let aws_utc_datestrings = aws_signature_builder::AwsUTCDateStrings::new();
-    let provider = DefaultCredentialsProvider::new().unwrap();
-    let credentials = provider.credentials().wait().unwrap();
-    let new_headers = aws_signature_builder::generate_aws_signature_headers(
-        aws_utc_datestrings,
-        credentials,
-        &mut request);
-    aws_signature_builder::add_aws_signature_headers(&mut request, new_headers);
-    request
+    let is_multi_region = request.uri().host()
+        .map_or(false, aws_signature_builder::sigv4a::is_multi_region_host);
+    let new_headers = if is_multi_region {
+        // Multi-Region Access Points can route the request to any region, so the signature has
+        // to be valid in all of them.
+        aws_signature_builder::sigv4a::generate_aws_signature_headers(
+            aws_utc_datestrings,
+            credentials,
+            &mut request,
+            payload,
+            &settings.signed_headers,
+            "s3",
+            "*")?
+    } else {
+        aws_signature_builder::generate_aws_signature_headers(
+            aws_utc_datestrings,
+            credentials,
+            &mut request,
+            payload,
+            &settings.signed_headers,
+            &settings.resolver)?
+    };
+    aws_signature_builder::add_aws_signature_headers(&mut request, new_headers)?;
+    Ok(request)
+}
+
+impl AddsAWSSignatureHeaders {
+    /// The payload is part of the signature, so buffer the whole body before signing and then
+    /// send the buffered body along with the signed headers.  Large S3 uploads are streamed as
+    /// signed chunks instead, see `aws_signature_builder::chunked`, and services configured with
+    /// `--unsigned-payload` are not buffered at all.
+    ///
+    /// Credentials come from the shared cache (or the one of the client's identity), so this only
+    /// waits for them when they have to be loaded.
+    fn sign(&self, mut req: Request<Body>) -> RequestFuture {
+        let settings = self.settings.clone();
+        // Removed before signing, since AWS has no business seeing it.
+        let profile_header = req.headers_mut().remove(credentials::clients::XPROXYPROFILE);
+        let cache = self.settings.clients
+            .identify(self.proxy_authorization.as_ref(), profile_header.as_ref());
+        let cache = match cache {
+            Ok(cache) => cache.unwrap_or(&self.settings.credentials),
+            Err(e) => return Box::new(future::ok(Err(proxy_authentication_required(e)))),
+        };
+        let credentials = cache.credentials()
+            .then(|credentials| Ok::<_, hyper::Error>(credentials.map_err(SigningError::from)));
+        if self.settings.uses_unsigned_payload(&req) {
+            return Box::new(credentials.map(move |credentials| {
+                credentials
+                    .and_then(|credentials| add_signature_headers(req,
+                        aws_signature_builder::Payload::Unsigned, credentials, &settings))
+                    .map_err(signing_error_response)
+            }));
+        }
+        let content_length = aws_signature_builder::chunked::streaming_content_length(
+            &req, &self.settings.resolver);
+        if let Some(content_length) = content_length {
+            let chunked_encoder = self.chunked_encoder.clone();
+            return Box::new(credentials.map(move |credentials| {
+                let mut req = req;
+                let encoder = credentials.and_then(|credentials| {
+                    aws_signature_builder::chunked::sign_streaming_request(
+                        aws_signature_builder::AwsUTCDateStrings::new(),
+                        credentials,
+                        &mut req,
+                        content_length,
+                        &settings.signed_headers,
+                        &settings.resolver)
+                });
+                encoder
+                    .map(|encoder| {
+                        *chunked_encoder.lock().unwrap() = Some(encoder);
+                        req
+                    })
+                    .map_err(signing_error_response)
+            }));
+        }
+        let (parts, body) = req.into_parts();
+        Box::new(body.concat2().join(credentials).map(move |(body, credentials)| {
+            let request = credentials.and_then(|credentials| add_signature_headers(
+                Request::from_parts(parts, Body::empty()),
+                aws_signature_builder::Payload::Signed(&body),
+                credentials,
+                &settings));
+            request
+                .map(|request| request.map(|_| Body::from(body)))
+                .map_err(signing_error_response)
+        }))
+    }
 }
 
 impl Mitm for AddsAWSSignatureHeaders {
-    fn new(uri: Uri) -> AddsAWSSignatureHeaders {
-        println!("proxying request for {}", uri);
-        AddsAWSSignatureHeaders { }
+    type State = ProxySettings;
+
+    fn host_policy(host: &str, settings: &ProxySettings) -> HostPolicy {
+        if settings.allowed_hosts.allows(host) || settings.resolver.has_override(host) {
+            HostPolicy::Intercept
+        } else {
+            settings.other_hosts
+        }
+    }
+
+    /// The real host is set as the `Host` too, since that is what gets signed.
+    fn route(mut req: Request<Body>, settings: &ProxySettings)
+        -> Result<Request<Body>, Response<Body>> {
+        if !settings.routes.is_enabled() {
+            return Err(mitm::error_response(StatusCode::NOT_FOUND, format!(
+                "{} is not a proxy request, see --reverse-proxy and --route", req.uri())));
+        }
+        let uri = settings.routes.resolve(req.uri()).ok_or_else(|| mitm::error_response(
+            StatusCode::NOT_FOUND, format!("no route for {}", req.uri())))?;
+        if let Some(authority) = uri.authority_part() {
+            let host = HeaderValue::from_str(authority.as_str())
+                .map_err(|e| mitm::error_response(StatusCode::BAD_GATEWAY, e.to_string()))?;
+            req.headers_mut().insert(HOST, host);
+        }
+        *req.uri_mut() = uri;
+        Ok(req)
+    }
+
+    fn new(uri: Uri, proxy_authorization: Option<HeaderValue>, settings: Arc<ProxySettings>)
+        -> AddsAWSSignatureHeaders {
+        if !settings.quiet {
+            println!("proxying request for {}", uri);
+        }
+        AddsAWSSignatureHeaders {
+            settings,
+            proxy_authorization,
+            chunked_encoder: Arc::new(Mutex::new(None)),
+        }
     }
 
-    fn request_headers(&self, req: Request<Body>) -> Request<Body> {
-        add_signature_headers(req)
+    /// Signs the request, and then points it at its `--upstream` if it has one.
+    fn request_headers(&self, req: Request<Body>) -> RequestFuture {
+        let settings = self.settings.clone();
+        Box::new(self.sign(req)
+            .map(move |req| req.map(|req| settings.upstreams.redirect(req))))
     }
 
     fn response_headers(&self, res: Response<Body>) -> Response<Body> {
@@ -49,7 +425,15 @@ impl Mitm for AddsAWSSignatureHeaders {
     }
 
     fn request_body_chunk(&self, chunk: Chunk) -> Chunk {
-        chunk
+        match self.chunked_encoder.lock().unwrap().as_mut() {
+            Some(encoder) => Chunk::from(encoder.push(&chunk)),
+            None => chunk,
+        }
+    }
+
+    fn request_body_end(&self) -> Option<Chunk> {
+        self.chunked_encoder.lock().unwrap().as_mut()
+            .map(|encoder| Chunk::from(encoder.finish()))
     }
 
     fn response_body_chunk(&self, chunk: Chunk) -> Chunk {
@@ -57,13 +441,401 @@ impl Mitm for AddsAWSSignatureHeaders {
     }
 }
 
+fn presign(
+    url: Uri,
+    method: String,
+    expires_in: u64,
+    credentials: CredentialsCache,
+    resolver: aws_signature_builder::endpoints::EndpointResolver) {
+    if expires_in > aws_signature_builder::presign::MAX_EXPIRES_IN {
+        eprintln!("--expires-in can be at most {} seconds",
+            aws_signature_builder::presign::MAX_EXPIRES_IN);
+        std::process::exit(1);
+    }
+    // Loading credentials may need an HTTP client (e.g. to assume a role), so run in the runtime.
+    hyper::rt::run(future::lazy(move || credentials.credentials().then(move |credentials| {
+        let credentials = match credentials {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                eprintln!("failed to load AWS credentials: {}", e);
+                std::process::exit(1);
+            },
+        };
+        println!("{}", aws_signature_builder::presign::presign_url(
+            aws_signature_builder::AwsUTCDateStrings::new(),
+            &credentials,
+            &method.to_uppercase(),
+            &url,
+            expires_in,
+            &resolver));
+        Ok(())
+    })));
+}
+
+/// Returns where credentials come from: the selected profile if there is one, and otherwise the
+/// same places the AWS SDKs look, with the `--role-arn`s assumed on top of that.
+fn credentials_source(args: &Cli, mfa_codes: &Arc<MfaCodes>)
+    -> Result<Box<dyn CredentialsSource>, String> {
+    let mut source = profile_source(args, mfa_codes)?;
+    if args.role_arn.is_empty() {
+        return Ok(source);
+    }
+    if let Some(duration_seconds) = args.role_duration_seconds {
+        if duration_seconds < 900 || duration_seconds > 43200 {
+            return Err(String::from("--role-duration-seconds must be between 900 and 43200"));
+        }
+        // STS refuses longer sessions for roles assumed with another role's credentials.
+        let chained = args.role_arn.len() > 1 || base_is_role(args, mfa_codes)?;
+        if duration_seconds > 3600 && chained {
+            return Err(String::from("--role-duration-seconds can't be more than 3600 when the \
+                --role-arn is assumed with another role's credentials"));
+        }
+    }
+    let sts_endpoint = args.sts_endpoint.as_ref().map(String::as_str);
+    let sts = credentials::sts::StsClient::new(None, sts_endpoint).map_err(|e| e.message)?;
+    for (i, role_arn) in args.role_arn.iter().enumerate() {
+        let mut role = credentials::sts::AssumeRole::new(role_arn);
+        if let Some(role_session_name) = &args.role_session_name {
+            role.role_session_name = role_session_name.clone();
+        }
+        role.external_id = args.external_id.clone();
+        role.duration_seconds = args.role_duration_seconds;
+        role.tags = args.role_tag.clone();
+        source = match &args.mfa_serial {
+            Some(mfa_serial) if i == 0 => {
+                role.serial_number = Some(mfa_serial.clone());
+                let base_name = credentials::profile::selected_profile(args.profile.clone())
+                    .unwrap_or_default();
+                Box::new(credentials::mfa::MfaRoleSource::new(sts.clone(), source, &base_name,
+                    role, mfa_codes.clone()))
+            },
+            _ => Box::new(credentials::sts::AssumeRoleSource::new(sts.clone(), source, role)),
+        };
+    }
+    Ok(source)
+}
+
+/// Whether the credentials the `--role-arn`s are assumed with are already a role's, from the
+/// selected profile or a web identity token.
+fn base_is_role(args: &Cli, mfa_codes: &Arc<MfaCodes>) -> Result<bool, String> {
+    match credentials::profile::selected_profile(args.profile.clone()) {
+        Some(profile) => load_profiles(args, mfa_codes)
+            .map(|profiles| profiles.assumes_role(&profile))
+            .map_err(|e| format!("can't use profile {}: {}", profile, e)),
+        None => Ok(std::env::var_os("AWS_ACCESS_KEY_ID").is_none()
+            && std::env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE").is_some()
+            && std::env::var_os("AWS_ROLE_ARN").is_some()),
+    }
+}
+
+fn load_profiles(args: &Cli, mfa_codes: &Arc<MfaCodes>)
+    -> Result<credentials::profile::Profiles, CredentialsError> {
+    credentials::profile::Profiles::load().map(|profiles| profiles
+        .sts_endpoint(args.sts_endpoint.clone())
+        .mfa_codes(mfa_codes.clone()))
+}
+
+/// Builds the credentials of every `--client`.
+fn client_identities(args: &Cli, credentials: &CredentialsCache, mfa_codes: &Arc<MfaCodes>)
+    -> Result<Clients, String> {
+    let mut clients = Clients::default();
+    for client in &args.client {
+        let source = match &client.role_arn {
+            Some(role_arn) => credentials::sts::StsClient::new(None,
+                args.sts_endpoint.as_ref().map(String::as_str)).map(|sts| {
+                let mut role = credentials::sts::AssumeRole::new(role_arn);
+                role.role_session_name = format!("{}-{}", client.name, role.role_session_name);
+                Box::new(credentials::sts::AssumeRoleSource::new(sts,
+                    Box::new(credentials.clone()), role)) as Box<dyn CredentialsSource>
+            }),
+            None => load_profiles(args, mfa_codes)
+                .and_then(|profiles| profiles.source(&client.name)),
+        };
+        let source = source
+            .map_err(|e| format!("can't use client identity {}: {}", client.name, e))?;
+        clients.insert(&client.name, &client.token, CredentialsCache::new(source));
+    }
+    Ok(clients)
+}
+
+fn profile_source(args: &Cli, mfa_codes: &Arc<MfaCodes>)
+    -> Result<Box<dyn CredentialsSource>, String> {
+    let profile = match credentials::profile::selected_profile(args.profile.clone()) {
+        Some(profile) => profile,
+        None => return default_source(args),
+    };
+    load_profiles(args, mfa_codes)
+        .and_then(|profiles| profiles.source(&profile))
+        .map_err(|e| format!("can't use profile {}: {}", profile, e))
+}
+
+/// Access keys in the environment win, then a web identity token (e.g. in an EKS pod), and then
+/// everything else rusoto's chain looks at.
+fn default_source(args: &Cli) -> Result<Box<dyn CredentialsSource>, String> {
+    if std::env::var_os("AWS_ACCESS_KEY_ID").is_some() {
+        return Ok(Box::new(ChainProvider::new()));
+    }
+    match credentials::sts::WebIdentitySource::from_env(
+        args.sts_endpoint.as_ref().map(String::as_str)) {
+        Some(Ok(source)) => Ok(Box::new(source)),
+        Some(Err(e)) => Err(format!("can't use the web identity token: {}", e)),
+        None => Ok(Box::new(ChainProvider::new())),
+    }
+}
+
+/// Prints who requests will be signed as, so it's obvious when the wrong profile is in use.
+fn report_identity(credentials: &CredentialsCache, sts_endpoint: Option<&str>)
+    -> impl Future<Item = (), Error = ()> {
+    future::result(credentials::sts::StsClient::new(None, sts_endpoint))
+        .join(credentials.credentials())
+        .and_then(|(sts, credentials)| sts.get_caller_identity(credentials))
+        .map(|arn| println!("signing requests as {}", arn))
+        .map_err(|e| eprintln!("can't tell who requests will be signed as: {}", e))
+}
+
+fn ca(command: &CaCommand, ca_dir: &Path) {
+    let result = match command {
+        CaCommand::Init { force } => CertificateAuthority::init(ca_dir, *force).map(|_| {
+            println!("created a CA in {}, trust it with the certificate from `ca export`",
+                ca_dir.display());
+        }),
+        CaCommand::Export => CertificateAuthority::load(ca_dir).and_then(|ca| {
+            let ca = ca.ok_or_else(|| format!("there is no CA in {}, see `ca init`",
+                ca_dir.display()))?;
+            print!("{}", String::from_utf8_lossy(&ca.cert_pem()?));
+            Ok(())
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Serves `svc` on `listener` until `shutdown` resolves, and then until the connections that are
+/// still open are done.
+fn serve(listener: &listen::Listen, svc: MitmProxyService<AddsAWSSignatureHeaders>,
+    socket_mode: u32, shutdown: signals::Shutdown)
+    -> Result<Box<dyn Future<Item = (), Error = ()> + Send>, String> {
+    let shutdown = shutdown.then(|_| Ok::<_, ()>(()));
+    let server: Box<dyn Future<Item = (), Error = hyper::Error> + Send> = match listener {
+        listen::Listen::Tcp(addr) => Box::new(Server::try_bind(addr)
+            .map_err(|e| e.to_string())?
+            .serve(svc)
+            .with_graceful_shutdown(shutdown)),
+        #[cfg(unix)]
+        listen::Listen::Unix(path) => Box::new(Server::builder(
+            listen::bind_unix(path, socket_mode).map_err(|e| e.to_string())?.incoming())
+            .serve(svc)
+            .with_graceful_shutdown(shutdown)),
+        #[cfg(not(unix))]
+        listen::Listen::Unix(_) => unreachable!("Unix sockets are refused by --listen"),
+    };
+    let listener = listener.to_string();
+    Ok(Box::new(server.map_err(move |e| eprintln!("server error on {}: {}", listener, e))))
+}
+
+/// `cli` with the settings from its `--config` file (if any) applied.
+fn load_args(cli: &Cli) -> Result<Cli, String> {
+    let mut args = cli.clone();
+    if let Some(path) = args.config.clone() {
+        config::Config::load(&path)?.apply(&mut args);
+    }
+    Ok(args)
+}
+
+fn ca_dir(args: &Cli) -> Result<PathBuf, String> {
+    args.ca_dir.clone().or_else(CertificateAuthority::default_dir)
+        .ok_or_else(|| String::from("can't find the home directory, see --ca-dir"))
+}
+
+fn load_ca(ca_dir: &Path) -> Result<Option<CertificateAuthority>, String> {
+    CertificateAuthority::load(ca_dir)
+        .map_err(|e| format!("can't load the CA from {}: {}", ca_dir.display(), e))
+}
+
+/// Everything requests are handled with, signed with `credentials` unless the client picks one
+/// of the `--client` identities.
+fn proxy_settings(args: &Cli, credentials: CredentialsCache, mfa_codes: &Arc<MfaCodes>)
+    -> Result<ProxySettings, String> {
+    let clients = client_identities(args, &credentials, mfa_codes)?;
+    let signed_headers = if args.sign_header.is_empty() {
+        aws_signature_builder::SignedHeadersPolicy::default()
+    } else {
+        aws_signature_builder::SignedHeadersPolicy::new(args.sign_header.clone())
+    };
+    let allowed_hosts = if args.allow_host.is_empty() {
+        aws_signature_builder::endpoints::HostAllowlist::default()
+    } else {
+        aws_signature_builder::endpoints::HostAllowlist::new(args.allow_host.clone())
+    };
+    let other_hosts = if args.reject_other_hosts {
+        HostPolicy::Reject
+    } else {
+        HostPolicy::PassThrough
+    };
+    Ok(ProxySettings {
+        credentials,
+        clients,
+        unsigned_payload_services: args.unsigned_payload.clone(),
+        signed_headers,
+        resolver: aws_signature_builder::endpoints::EndpointResolver::new(
+            args.signing_override.clone()),
+        allowed_hosts,
+        other_hosts,
+        routes: routes::Routes::new(args.route.clone(), args.reverse_proxy),
+        upstreams: routes::Upstreams::new(args.upstream.clone()),
+        quiet: args.quiet,
+    })
+}
+
+/// Reads the `--config` file again and handles every new request with what it says now, and
+/// resolves with the new settings.  Nothing changes unless everything loaded, including the
+/// credentials.  Listeners, `--metadata-port` and `--upstream-proxy` stay as they were.
+fn reload(cli: &Cli, svc: &MitmProxyService<AddsAWSSignatureHeaders>,
+    credentials: &CredentialsCache, mfa_codes: &Arc<MfaCodes>)
+    -> Box<dyn Future<Item = Cli, Error = String> + Send> {
+    let loaded = load_args(cli).and_then(|args| {
+        let source = credentials_source(&args, mfa_codes)?;
+        let settings = proxy_settings(&args, credentials.clone(), mfa_codes)?;
+        let ca = load_ca(&ca_dir(&args)?)?;
+        Ok((args, source, settings, ca))
+    });
+    let (args, source, settings, ca) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let svc = svc.clone();
+    let credentials = credentials.clone();
+    Box::new(source.load()
+        .map_err(|e| format!("can't load the new credentials: {}", e))
+        .map(move |loaded| {
+            credentials.replace_source(source, loaded);
+            svc.reload(settings, ca);
+            args
+        }))
+}
+
+/// Exits on errors, which at startup means the proxy can't run as configured.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
 fn main() {
-    let args = Cli::from_args();
-    let addr = ([127, 0, 0, 1], args.port).into();
-    let svc = MitmProxyService::<AddsAWSSignatureHeaders>::new();
-    let server = Server::bind(&addr)
-        .serve(svc)
-        .map_err(|e| eprintln!("server error: {}", e));
-    println!("add-via mitm proxy listening on http://{}", addr);
-    hyper::rt::run(server);
+    let cli = Cli::from_args();
+    let args = or_exit(load_args(&cli));
+    let ca_dir = or_exit(ca_dir(&args));
+    if let Some(Command::Ca { command }) = &args.command {
+        ca(command, &ca_dir);
+        return;
+    }
+    let upstream_proxy = or_exit(args.upstream_proxy.clone().map(|proxy| Ok(Some(proxy)))
+        .unwrap_or_else(egress::UpstreamProxy::from_env))
+        .map(egress::UpstreamProxy::no_proxy_from_env);
+    let upstream_proxy_address = upstream_proxy.as_ref().map(ToString::to_string);
+    egress::set_upstream_proxy(upstream_proxy);
+    let mfa_codes = Arc::new(MfaCodes::new(args.mfa_token.clone()));
+    let credentials = CredentialsCache::new(or_exit(credentials_source(&args, &mfa_codes)));
+    let settings = or_exit(proxy_settings(&args, credentials.clone(), &mfa_codes));
+    if let Some(Command::Presign { url, method, expires_in }) = args.command {
+        presign(url, method, expires_in, credentials, settings.resolver);
+        return;
+    }
+    let mut listeners = args.listen.clone();
+    if let Some(port) = args.port {
+        listeners.insert(0, listen::Listen::local(port));
+    }
+    if listeners.is_empty() {
+        eprintln!("A port or --listen address to listen on is required, see --help");
+        std::process::exit(1);
+    }
+    if args.client.is_empty() {
+        for listener in listeners.iter().filter(|listener| !listener.is_local()) {
+            eprintln!("anyone who can reach {} can sign requests with the proxy's credentials, \
+                       see --client", listener);
+        }
+    }
+    let sts_endpoint = args.sts_endpoint.clone();
+    let shutdown = signals::shutdown();
+    let metadata = args.metadata_port.map(|metadata_port| {
+        let addr = ([127, 0, 0, 1], metadata_port).into();
+        let authorization_token = args.container_authorization_token.clone()
+            .unwrap_or_else(metadata::random_token);
+        println!("serving credentials for tools that can't use the proxy, with either of:");
+        println!("  AWS_EC2_METADATA_SERVICE_ENDPOINT=http://{}", addr);
+        println!("  AWS_CONTAINER_CREDENTIALS_FULL_URI=http://{}{} \\", addr,
+            metadata::CONTAINER_CREDENTIALS_PATH);
+        println!("    AWS_CONTAINER_AUTHORIZATION_TOKEN={}", authorization_token);
+        metadata::MetadataService::new(credentials.clone(), authorization_token)
+            .serve(&addr, shutdown.clone())
+    });
+    let svc = match or_exit(load_ca(&ca_dir)) {
+        Some(ca) => MitmProxyService::<AddsAWSSignatureHeaders>::new(settings).ca(ca),
+        None => {
+            println!("no CA in {}, presenting self signed certificates (see `ca init`)",
+                ca_dir.display());
+            MitmProxyService::new(settings)
+        },
+    };
+    let svc = svc.graceful_shutdown(shutdown.clone());
+    let socket_mode = args.socket_mode.unwrap_or(0o600);
+    let servers = listeners.iter()
+        .map(|listener| {
+            let server = serve(listener, svc.clone(), socket_mode, shutdown.clone())
+                .unwrap_or_else(|e| {
+                    eprintln!("can't listen on {}: {}", listener, e);
+                    std::process::exit(1);
+                });
+            println!("add-via mitm proxy listening on {}", listener);
+            server
+        })
+        .collect::<Vec<_>>();
+    if let Some(upstream_proxy_address) = upstream_proxy_address {
+        println!("connecting through the upstream proxy {}", upstream_proxy_address);
+    }
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout.unwrap_or(30));
+    hyper::rt::run(future::lazy(move || {
+        hyper::rt::spawn(report_identity(&credentials,
+            sts_endpoint.as_ref().map(String::as_str)));
+        if let Some(metadata) = metadata {
+            hyper::rt::spawn(metadata);
+        }
+        for server in servers {
+            hyper::rt::spawn(server);
+        }
+        let reloads = signals::reloads()
+            .for_each(move |()| {
+                let credentials = credentials.clone();
+                reload(&cli, &svc, &credentials, &mfa_codes).then(move |result| {
+                    match result {
+                        Ok(args) => {
+                            println!("reloaded the configuration");
+                            hyper::rt::spawn(report_identity(&credentials,
+                                args.sts_endpoint.as_ref().map(String::as_str)));
+                        },
+                        Err(e) => eprintln!("not reloading, keeping the old configuration: {}",
+                            e),
+                    }
+                    Ok(())
+                })
+            })
+            .select(shutdown.clone().then(|_| Ok(())))
+            .then(|_| Ok(()));
+        hyper::rt::spawn(reloads);
+        // Tunnels that are passed through can't be told to finish up, so don't wait for them
+        // forever.
+        shutdown.then(move |_| {
+            println!("shutting down once open connections are done, for at most {} seconds",
+                shutdown_timeout.as_secs());
+            thread::spawn(move || {
+                thread::sleep(shutdown_timeout);
+                eprintln!("exiting with connections still open");
+                std::process::exit(0);
+            });
+            Ok(())
+        })
+    }));
 }
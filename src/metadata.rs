@@ -0,0 +1,240 @@
+//! Local stand-ins for the EC2 instance metadata service (IMDSv2) and the ECS container
+//! credentials endpoint, handing out the same credentials the proxy signs with.  That way tools
+//! that can't use a proxy, but do look for credentials in one of those places, can still use them.
+//!
+//! SDKs find them with `AWS_EC2_METADATA_SERVICE_ENDPOINT=http://127.0.0.1:PORT`, or with
+//! `AWS_CONTAINER_CREDENTIALS_FULL_URI=http://127.0.0.1:PORT/ecs/credentials` and
+//! `AWS_CONTAINER_AUTHORIZATION_TOKEN`.
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use futures::future::{self, Future};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use rusoto_credential::AwsCredentials;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::credentials::CredentialsCache;
+use crate::mitm::{error_response, ResponseFuture};
+use crate::signals::Shutdown;
+
+/// The only role the metadata service knows about.
+const ROLE_NAME: &str = "aws-signature-proxy";
+const TOKEN_PATH: &str = "/latest/api/token";
+const CREDENTIALS_PATH: &str = "/latest/meta-data/iam/security-credentials/";
+pub const CONTAINER_CREDENTIALS_PATH: &str = "/ecs/credentials";
+
+const XAWSEC2METADATATOKEN: &str = "x-aws-ec2-metadata-token";
+const XAWSEC2METADATATOKENTTLSECONDS: &str = "x-aws-ec2-metadata-token-ttl-seconds";
+
+/// The longest an IMDSv2 token can be valid for, six hours like on EC2.
+const MAX_TOKEN_TTL: i64 = 6 * 60 * 60;
+
+/// Credentials that never expire are handed out as if they did after this many seconds, since
+/// SDKs expect every credential from these endpoints to expire.
+const DEFAULT_EXPIRATION: i64 = 60 * 60;
+
+#[derive(Clone)]
+pub struct MetadataService {
+    credentials: CredentialsCache,
+    /// The `Authorization` the container credentials endpoint expects.
+    authorization_token: String,
+    /// IMDSv2 tokens that have been handed out, and when they expire.
+    tokens: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl MetadataService {
+    pub fn new(credentials: CredentialsCache, authorization_token: String) -> Self {
+        MetadataService {
+            credentials,
+            authorization_token,
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Listens on `addr` until `shutdown` resolves.
+    pub fn serve(self, addr: &SocketAddr, shutdown: Shutdown)
+        -> impl Future<Item = (), Error = ()> {
+        Server::bind(addr)
+            .serve(move || {
+                let service = self.clone();
+                service_fn(move |req| service.call(req))
+            })
+            .with_graceful_shutdown(shutdown.then(|_| Ok::<_, ()>(())))
+            .map_err(|e| eprintln!("metadata server error: {}", e))
+    }
+
+    fn call(&self, req: Request<Body>) -> ResponseFuture {
+        let path = req.uri().path();
+        if path == CONTAINER_CREDENTIALS_PATH {
+            return self.container_credentials(&req);
+        }
+        if path == TOKEN_PATH && req.method() == Method::PUT {
+            return Box::new(future::ok(self.new_token(&req)));
+        }
+        if !self.has_valid_token(&req) {
+            return Box::new(future::ok(error_response(StatusCode::UNAUTHORIZED,
+                String::from("an unexpired IMDSv2 token is required"))));
+        }
+        if path == CREDENTIALS_PATH || path == CREDENTIALS_PATH.trim_end_matches('/') {
+            return Box::new(future::ok(Response::new(Body::from(ROLE_NAME))));
+        }
+        if path.starts_with(CREDENTIALS_PATH) && path[CREDENTIALS_PATH.len()..] == *ROLE_NAME {
+            return self.credentials_response(true);
+        }
+        Box::new(future::ok(error_response(StatusCode::NOT_FOUND,
+            format!("the metadata service doesn't know about {}", path))))
+    }
+
+    /// Hands out an IMDSv2 token.  Like on EC2, requests that went through a proxy don't get one.
+    fn new_token(&self, req: &Request<Body>) -> Response<Body> {
+        if req.headers().contains_key("x-forwarded-for") {
+            return error_response(StatusCode::FORBIDDEN,
+                String::from("IMDSv2 tokens are not handed out through proxies"));
+        }
+        let ttl = req.headers().get(XAWSEC2METADATATOKENTTLSECONDS)
+            .and_then(|ttl| ttl.to_str().ok())
+            .and_then(|ttl| ttl.parse::<i64>().ok())
+            .filter(|ttl| *ttl > 0 && *ttl <= MAX_TOKEN_TTL);
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => return error_response(StatusCode::BAD_REQUEST, format!(
+                "{} must be between 1 and {}", XAWSEC2METADATATOKENTTLSECONDS, MAX_TOKEN_TTL)),
+        };
+        let token = random_token();
+        let now = Utc::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, expires_at| *expires_at > now);
+        tokens.insert(token.clone(), now + Duration::seconds(ttl));
+        let mut res = Response::new(Body::from(token));
+        res.headers_mut().insert(XAWSEC2METADATATOKENTTLSECONDS, ttl.into());
+        res
+    }
+
+    fn has_valid_token(&self, req: &Request<Body>) -> bool {
+        req.headers().get(XAWSEC2METADATATOKEN)
+            .and_then(|token| token.to_str().ok())
+            .and_then(|token| self.tokens.lock().unwrap().get(token).cloned())
+            .map_or(false, |expires_at| expires_at > Utc::now())
+    }
+
+    fn container_credentials(&self, req: &Request<Body>) -> ResponseFuture {
+        let authorized = req.headers().get(hyper::header::AUTHORIZATION)
+            .map_or(false, |authorization| {
+                let expected = self.authorization_token.as_bytes();
+                authorization.len() == expected.len()
+                    && openssl::memcmp::eq(authorization.as_bytes(), expected)
+            });
+        if !authorized {
+            return Box::new(future::ok(error_response(StatusCode::FORBIDDEN,
+                String::from("wrong or missing container authorization token"))));
+        }
+        self.credentials_response(false)
+    }
+
+    fn credentials_response(&self, imds: bool) -> ResponseFuture {
+        Box::new(self.credentials.credentials().then(move |credentials| {
+            let res = match credentials {
+                Ok(credentials) => {
+                    let mut res = Response::new(Body::from(
+                        credentials_json(&credentials, imds).to_string()));
+                    res.headers_mut().insert(hyper::header::CONTENT_TYPE,
+                        hyper::header::HeaderValue::from_static("application/json"));
+                    res
+                },
+                Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE,
+                    format!("failed to load AWS credentials: {}", e)),
+            };
+            Ok(res)
+        }))
+    }
+}
+
+/// The credentials as the metadata service (`imds`) or the container endpoint would return them.
+fn credentials_json(credentials: &AwsCredentials, imds: bool) -> serde_json::Value {
+    let expiration = (*credentials.expires_at())
+        .unwrap_or_else(|| Utc::now() + Duration::seconds(DEFAULT_EXPIRATION));
+    let mut json = serde_json::json!({
+        "AccessKeyId": credentials.aws_access_key_id(),
+        "SecretAccessKey": credentials.aws_secret_access_key(),
+        "Token": credentials.token(),
+        "Expiration": expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+    });
+    if imds {
+        json["Code"] = "Success".into();
+        json["Type"] = "AWS-HMAC".into();
+        json["LastUpdated"] = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into();
+    }
+    json
+}
+
+/// A token nobody can guess, for IMDSv2 sessions and the default container authorization token.
+pub fn random_token() -> String {
+    let mut bytes = [0; 32];
+    openssl::rand::rand_bytes(&mut bytes).expect("failed to generate a random token");
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use rusoto_credential::StaticProvider;
+
+    use crate::credentials::CredentialsCache;
+
+    fn service() -> super::MetadataService {
+        let credentials = CredentialsCache::new(Box::new(StaticProvider::new(
+            String::from("AKID"), String::from("secret"), Some(String::from("token")), None)));
+        super::MetadataService::new(credentials, String::from("s3cret"))
+    }
+
+    fn body(res: Response<Body>) -> String {
+        String::from_utf8(res.into_body().concat2().wait().unwrap().to_vec()).unwrap()
+    }
+
+    fn get(path: &str, header: (&str, &str)) -> Request<Body> {
+        Request::get(path).header(header.0, header.1).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_imds() {
+        let service = service();
+        let token = Request::builder().method(Method::PUT).uri("/latest/api/token")
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "21600")
+            .body(Body::empty()).unwrap();
+        let token = body(service.call(token).wait().unwrap());
+        let res = service.call(get("/latest/meta-data/iam/security-credentials/",
+            ("x-aws-ec2-metadata-token", token.as_str()))).wait().unwrap();
+        assert_eq!(body(res), "aws-signature-proxy");
+        let res = service.call(get("/latest/meta-data/iam/security-credentials/aws-signature-proxy",
+            ("x-aws-ec2-metadata-token", token.as_str()))).wait().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body(res)).unwrap();
+        assert_eq!(json["Code"], "Success");
+        assert_eq!(json["AccessKeyId"], "AKID");
+        assert_eq!(json["Token"], "token");
+        assert!(json["Expiration"].is_string());
+        // IMDSv1 requests and made up tokens are turned away.
+        let res = service.call(get("/latest/meta-data/iam/security-credentials/",
+            ("x-aws-ec2-metadata-token", "guess"))).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let token = Request::builder().method(Method::PUT).uri("/latest/api/token")
+            .body(Body::empty()).unwrap();
+        assert_eq!(service.call(token).wait().unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_container_credentials() {
+        let service = service();
+        let res = service.call(get("/ecs/credentials", ("authorization", "s3cret"))).wait()
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body(res)).unwrap();
+        assert_eq!(json["SecretAccessKey"], "secret");
+        assert!(json.get("Code").is_none());
+        let res = service.call(get("/ecs/credentials", ("authorization", "guess"))).wait()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
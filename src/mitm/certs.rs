@@ -0,0 +1,253 @@
+//! Certificates that the proxy presents to clients inside a `CONNECT` tunnel.
+//!
+//! If `MONIE_CERT_FILE` and `MONIE_KEY_FILE` are set, that certificate is used for every host.
+//! Otherwise, if a `CertificateAuthority` was created with `ca init`, each host gets its own
+//! certificate signed by it, so clients that trust the CA trust every host, however many labels
+//! deep.  Without either we generate a standalone self signed certificate for each host on demand,
+//! which clients will not trust.
+//!
+//! Certificates are made for the host the client asked to `CONNECT` to, which is the same one it
+//! sends as the SNI of the TLS connection inside the tunnel.
+
+use lazy_static::lazy_static;
+use native_tls::Identity;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::extension::{AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage,
+    KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier};
+use openssl::x509::{X509Name, X509NameBuilder, X509NameRef, X509};
+use tokio_tls::TlsAcceptor;
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const CERT_FILE_VAR: &str = "MONIE_CERT_FILE";
+const KEY_FILE_VAR: &str = "MONIE_KEY_FILE";
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+
+/// How long the CA is valid for.
+const CA_DAYS: u32 = 10 * 365;
+
+/// How long host certificates are valid for, which is less than the 398 days some clients (like
+/// macOS) accept at most.
+const HOST_DAYS: u32 = 365;
+
+lazy_static! {
+    static ref ACCEPTORS: Mutex<HashMap<String, TlsAcceptor>> = Mutex::new(HashMap::new());
+    static ref SELF_SIGNED_KEY: PKey<Private> = Rsa::generate(2048)
+        .and_then(PKey::from_rsa)
+        .expect("failed to generate a key for self signed certificates");
+}
+
+/// A CA kept on disk, whose certificate can be added to trust stores.
+pub struct CertificateAuthority {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl CertificateAuthority {
+    /// `~/.aws-signature-proxy`, where the CA is kept unless told otherwise.
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".aws-signature-proxy"))
+    }
+
+    /// Creates a new CA in `dir`.  An existing one is only replaced if `force` is set, since every
+    /// trust store that has it would have to be updated.
+    pub fn init(dir: &Path, force: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !force && dir.join(CA_CERT_FILE).exists() {
+            return Err(format!("there already is a CA in {}", dir.display()).into());
+        }
+        let key = Rsa::generate(2048).and_then(PKey::from_rsa)?;
+        let cert = ca_certificate(&key)?;
+        fs::create_dir_all(dir)?;
+        write_private(&dir.join(CA_KEY_FILE), &key.private_key_to_pem_pkcs8()?)?;
+        fs::write(dir.join(CA_CERT_FILE), cert.to_pem()?)?;
+        Ok(CertificateAuthority { cert, key })
+    }
+
+    /// Loads the CA from `dir`, or returns `None` if there isn't one.
+    pub fn load(dir: &Path) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let cert_file = dir.join(CA_CERT_FILE);
+        if !cert_file.exists() {
+            return Ok(None);
+        }
+        let cert = X509::from_pem(&fs::read(cert_file)?)?;
+        let key = PKey::private_key_from_pem(&fs::read(dir.join(CA_KEY_FILE))?)?;
+        Ok(Some(CertificateAuthority { cert, key }))
+    }
+
+    pub fn cert_pem(&self) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        self.cert.to_pem()
+    }
+}
+
+/// Returns a TLS acceptor that presents a certificate for `host`, creating it if necessary.
+pub fn acceptor_for_host(host: &str, ca: Option<&CertificateAuthority>)
+    -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let mut acceptors = ACCEPTORS.lock().unwrap();
+    if let Some(acceptor) = acceptors.get(host) {
+        return Ok(acceptor.clone());
+    }
+    let identity = match (env::var(CERT_FILE_VAR), env::var(KEY_FILE_VAR), ca) {
+        (Ok(cert_file), Ok(key_file), _) => identity_from_files(&cert_file, &key_file)?,
+        (_, _, Some(ca)) => ca_signed_identity(host, ca)?,
+        _ => self_signed_identity(host)?,
+    };
+    let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+    acceptors.insert(host.to_string(), acceptor.clone());
+    Ok(acceptor)
+}
+
+/// Makes `acceptor_for_host` create every certificate again, e.g. after the files changed.
+pub fn forget_certificates() {
+    ACCEPTORS.lock().unwrap().clear();
+}
+
+fn identity_from_files(cert_file: &str, key_file: &str)
+    -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let cert_pem = fs::read(cert_file)?;
+    // Accept both PKCS#1 ("RSA PRIVATE KEY") and PKCS#8 keys, since mkcert and friends often
+    // give you the former.
+    let key = PKey::private_key_from_pem(&fs::read(key_file)?)?;
+    Ok(Identity::from_pkcs8(&cert_pem, &key.private_key_to_pem_pkcs8()?)?)
+}
+
+fn self_signed_identity(host: &str) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let key: &PKey<Private> = &SELF_SIGNED_KEY;
+    let cert = host_certificate(host, key, None)?;
+    Ok(Identity::from_pkcs8(&cert.to_pem()?, &key.private_key_to_pem_pkcs8()?)?)
+}
+
+/// The host certificate is followed by the CA's, so clients get the whole chain.
+fn ca_signed_identity(host: &str, ca: &CertificateAuthority)
+    -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let key: &PKey<Private> = &SELF_SIGNED_KEY;
+    let mut chain = host_certificate(host, key, Some(ca))?.to_pem()?;
+    chain.extend(ca.cert.to_pem()?);
+    Ok(Identity::from_pkcs8(&chain, &key.private_key_to_pem_pkcs8()?)?)
+}
+
+fn name(common_name: &str) -> Result<X509Name, openssl::error::ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    Ok(name.build())
+}
+
+fn serial_number() -> Result<openssl::asn1::Asn1Integer, openssl::error::ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+fn ca_certificate(key: &PKey<Private>) -> Result<X509, openssl::error::ErrorStack> {
+    let name = name("aws-signature-proxy CA")?;
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial_number = serial_number()?;
+    builder.set_serial_number(&serial_number)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CA_DAYS)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(BasicConstraints::new().critical().ca().pathlen(0).build()?)?;
+    builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+    let subject_key_identifier = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(subject_key_identifier)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+/// A certificate for `host`, signed by `ca` or else by itself.
+fn host_certificate(host: &str, key: &PKey<Private>, ca: Option<&CertificateAuthority>)
+    -> Result<X509, openssl::error::ErrorStack> {
+    let name = name(host)?;
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial_number = serial_number()?;
+    builder.set_serial_number(&serial_number)?;
+    builder.set_subject_name(&name)?;
+    let issuer: &X509NameRef = match ca {
+        Some(ca) => ca.cert.subject_name(),
+        None => &name,
+    };
+    builder.set_issuer_name(issuer)?;
+    builder.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(HOST_DAYS)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let mut subject_alt_name = SubjectAlternativeName::new();
+    match host.parse::<IpAddr>() {
+        Ok(_) => subject_alt_name.ip(host),
+        Err(_) => subject_alt_name.dns(host),
+    };
+    let subject_alt_name = subject_alt_name
+        .build(&builder.x509v3_context(ca.map(|ca| &*ca.cert), None))?;
+    builder.append_extension(subject_alt_name)?;
+    if let Some(ca) = ca {
+        builder.append_extension(BasicConstraints::new().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().digital_signature()
+            .key_encipherment().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+        let authority_key_identifier = AuthorityKeyIdentifier::new().keyid(false)
+            .build(&builder.x509v3_context(Some(&ca.cert), None))?;
+        builder.append_extension(authority_key_identifier)?;
+        builder.sign(&ca.key, MessageDigest::sha256())?;
+    } else {
+        builder.sign(key, MessageDigest::sha256())?;
+    }
+    Ok(builder.build())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_ca_signed_certificate() {
+        let dir = std::env::temp_dir().join(format!("ca-test-{}", std::process::id()));
+        let created = super::CertificateAuthority::init(&dir, false).unwrap();
+        assert!(super::CertificateAuthority::init(&dir, false).is_err());
+        let ca = super::CertificateAuthority::load(&dir).unwrap().unwrap();
+        assert_eq!(ca.cert_pem().unwrap(), created.cert_pem().unwrap());
+        // More than one label below a wildcard, which mkcert's certificates couldn't cover.
+        let host = "bucket.s3.us-east-1.amazonaws.com";
+        let cert = super::host_certificate(host, &super::SELF_SIGNED_KEY, Some(&ca)).unwrap();
+        assert!(cert.verify(&ca.cert.public_key().unwrap()).unwrap());
+        assert_eq!(ca.cert.issued(&cert), openssl::x509::X509VerifyResult::OK);
+        let names: Vec<String> = cert.subject_alt_names().unwrap().iter()
+            .filter_map(|name| name.dnsname().map(String::from))
+            .collect();
+        assert_eq!(names, vec![String::from(host)]);
+        assert!(super::CertificateAuthority::load(&dir.join("missing")).unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+//! A small man in the middle proxy, modeled after [monie](https://github.com/nlevitt/monie).
+//!
+//! Clients use this as their `https_proxy`.  For every `CONNECT` we terminate TLS ourselves with a
+//! certificate for the requested host (see `certs`), so we can see and modify the requests inside
+//! the tunnel before sending them on to the real endpoint.
+//!
+//! The main difference from monie is that `Mitm::request_headers` returns a future.  That lets an
+//! implementation wait for the whole request body before deciding on the headers, which is what
+//! we need to sign requests that have a payload.
+//!
+//! Hosts that `Mitm::host_policy` says to leave alone are tunneled through untouched, without
+//! terminating TLS, or refused.  Requests made to the proxy itself instead of through it can be
+//! sent somewhere with `Mitm::route`, which makes it work as a reverse proxy too.
+//!
+//! The state and CA can be swapped with `MitmProxyService::reload` while the proxy runs.  Requests
+//! that already started keep the state they started with, and the next request, even inside an
+//! open tunnel, gets the new one.
+
+pub mod certs;
+
+use futures::future::{self, Future};
+use futures::{Async, Stream};
+use http::uri::{Authority, PathAndQuery, Scheme, Uri};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, NewService, Service};
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Chunk, Client, Method, Request, Response, StatusCode};
+use tokio::io::{copy, shutdown, AsyncRead};
+
+use std::sync::{Arc, RwLock};
+
+use crate::credentials::clients::XPROXYPROFILE;
+use crate::egress;
+use crate::signals::Shutdown;
+
+/// Resolves to the request to forward, or to a response to answer the client with instead.
+pub type RequestFuture =
+    Box<dyn Future<Item = Result<Request<Body>, Response<Body>>, Error = hyper::Error> + Send>;
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+type HttpsClient = Client<egress::HttpsConnector>;
+
+/// Set on every response the proxy makes up itself, so clients can tell them apart from responses
+/// that came from the real endpoint.
+const XPROXYERROR: &str = "x-proxy-error";
+
+/// What to do with the requests to a host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostPolicy {
+    /// Run them through the `Mitm` hooks.
+    Intercept,
+    /// Send them on as they are, and tunnel `CONNECT`s without looking inside.
+    PassThrough,
+    /// Answer them with `403 Forbidden`.
+    Reject,
+}
+
+/// Hooks that get called for each request going through the proxy.
+///
+/// A new instance is created for every request, with the full `https://` uri of that request, the
+/// `Proxy-Authorization` the client sent (on the `CONNECT` for requests inside a tunnel), and the
+/// state that was given to `MitmProxyService::new`.  `Proxy-Authorization` is never forwarded.
+pub trait Mitm {
+    /// Shared by every request going through the proxy, e.g. settings from the command line.
+    type State: Send + Sync + 'static;
+
+    /// Called with the host of every `CONNECT` and plain http request before anything else.
+    fn host_policy(_host: &str, _state: &Self::State) -> HostPolicy {
+        HostPolicy::Intercept
+    }
+
+    /// Called with requests that only have a path, because they were made to the proxy itself,
+    /// to point them at a real host.  By default they are refused.
+    fn route(req: Request<Body>, _state: &Self::State) -> Result<Request<Body>, Response<Body>> {
+        Err(error_response(StatusCode::NOT_FOUND,
+            format!("{} is not a proxy request", req.uri())))
+    }
+
+    fn new(uri: Uri, proxy_authorization: Option<HeaderValue>, state: Arc<Self::State>) -> Self;
+
+    /// Called with the whole request before it is forwarded.  The body may be consumed here (for
+    /// example to hash it), as long as the returned request carries an equivalent body.  Returning
+    /// a response instead (e.g. from `error_response`) sends that back without forwarding anything.
+    fn request_headers(&self, req: Request<Body>) -> RequestFuture;
+
+    fn response_headers(&self, res: Response<Body>) -> Response<Body>;
+
+    fn request_body_chunk(&self, chunk: Chunk) -> Chunk;
+
+    /// Called once the whole request body has gone through `request_body_chunk`, in case anything
+    /// still needs to be sent after it.
+    fn request_body_end(&self) -> Option<Chunk> {
+        None
+    }
+
+    fn response_body_chunk(&self, chunk: Chunk) -> Chunk;
+}
+
+/// What `MitmProxyService::reload` replaces.
+struct Current<S> {
+    state: Arc<S>,
+    /// Signs the certificates presented to clients, see `certs`.
+    ca: Option<Arc<certs::CertificateAuthority>>,
+}
+
+impl<S> Clone for Current<S> {
+    fn clone(&self) -> Self {
+        Current { state: self.state.clone(), ca: self.ca.clone() }
+    }
+}
+
+pub struct MitmProxyService<T: Mitm> {
+    client: HttpsClient,
+    /// Opens the tunnels for hosts that are passed through.
+    connector: egress::Connector,
+    /// Shared by every clone, so a reload reaches all of them.
+    current: Arc<RwLock<Current<T::State>>>,
+    /// Tells the connections served inside tunnels to finish up.
+    shutdown: Option<Shutdown>,
+}
+
+impl<T: Mitm> MitmProxyService<T> {
+    pub fn new(state: T::State) -> Self {
+        let https = egress::https_connector(4)
+            .expect("failed to initialize TLS for the proxy client");
+        MitmProxyService {
+            client: Client::builder().build(https),
+            connector: egress::Connector::new(1),
+            current: Arc::new(RwLock::new(Current { state: Arc::new(state), ca: None })),
+            shutdown: None,
+        }
+    }
+
+    /// Presents certificates signed by `ca` instead of self signed ones.
+    pub fn ca(self, ca: certs::CertificateAuthority) -> Self {
+        self.current.write().unwrap().ca = Some(Arc::new(ca));
+        self
+    }
+
+    /// Once `shutdown` resolves, connections inside tunnels are closed as soon as they are idle.
+    pub fn graceful_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Replaces the state and CA for every request from now on.  Certificates are made again, in
+    /// case their files changed too.
+    pub fn reload(&self, state: T::State, ca: Option<certs::CertificateAuthority>) {
+        *self.current.write().unwrap() = Current { state: Arc::new(state), ca: ca.map(Arc::new) };
+        certs::forget_certificates();
+    }
+}
+
+impl<T: Mitm> Clone for MitmProxyService<T> {
+    fn clone(&self) -> Self {
+        MitmProxyService {
+            client: self.client.clone(),
+            connector: self.connector.clone(),
+            current: self.current.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+impl<T: Mitm + Send + Sync + 'static> NewService for MitmProxyService<T> {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = MitmProxyService<T>;
+    type Future = future::FutureResult<Self::Service, Self::InitError>;
+    type InitError = hyper::Error;
+
+    fn new_service(&self) -> Self::Future {
+        future::ok(self.clone())
+    }
+}
+
+impl<T: Mitm + Send + Sync + 'static> Service for MitmProxyService<T> {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = ResponseFuture;
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let state = self.current.read().unwrap().state.clone();
+        let req = if req.method() != Method::CONNECT && req.uri().authority_part().is_none() {
+            match T::route(req, &state) {
+                Ok(req) => req,
+                Err(res) => return Box::new(future::ok(res)),
+            }
+        } else {
+            req
+        };
+        let policy = T::host_policy(req.uri().host().unwrap_or(""), &state);
+        match (policy, req.method() == Method::CONNECT) {
+            (HostPolicy::Reject, _) => Box::new(future::ok(error_response(StatusCode::FORBIDDEN,
+                format!("the proxy doesn't allow requests to {}", req.uri())))),
+            (HostPolicy::PassThrough, true) => tunnel(&self.connector, req),
+            (HostPolicy::PassThrough, false) => pass_through(&self.client, req),
+            (HostPolicy::Intercept, true) => self.proxy_connect(req),
+            (HostPolicy::Intercept, false) => forward::<T>(&self.client, &state, None, req),
+        }
+    }
+}
+
+impl<T: Mitm + Send + Sync + 'static> MitmProxyService<T> {
+    /// Answers the `CONNECT` and then serves the tunneled connection ourselves, forwarding every
+    /// request inside of it to the host the client asked for.
+    fn proxy_connect(&self, req: Request<Body>) -> ResponseFuture {
+        let authority = match req.uri().authority_part() {
+            Some(authority) => authority.clone(),
+            None => return Box::new(future::ok(error_response(StatusCode::BAD_REQUEST,
+                format!("CONNECT requires a host, got {}", req.uri())))),
+        };
+        let ca = self.current.read().unwrap().ca.clone();
+        let ca = ca.as_ref().map(|ca| &**ca);
+        let acceptor = match certs::acceptor_for_host(authority.host(), ca) {
+            Ok(acceptor) => acceptor,
+            Err(e) => return Box::new(future::ok(error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                format!("could not create a certificate for {}: {}", authority.host(), e)))),
+        };
+        let client = self.client.clone();
+        let current = self.current.clone();
+        let shutdown = self.shutdown.clone();
+        let proxy_authorization = req.headers().get(PROXY_AUTHORIZATION).cloned();
+        let tunnel = req.into_body().on_upgrade()
+            .map_err(|e| eprintln!("upgrade error: {}", e))
+            .and_then(move |upgraded| {
+                acceptor.accept(upgraded).map_err(|e| eprintln!("tls error: {}", e))
+            })
+            .and_then(move |stream| {
+                let service = service_fn(move |req: Request<Body>| {
+                    let req = with_absolute_uri(req, &authority);
+                    let state = current.read().unwrap().state.clone();
+                    // The policy may have changed with a reload since the tunnel was opened.
+                    match T::host_policy(authority.host(), &state) {
+                        HostPolicy::Reject => Box::new(future::ok(error_response(
+                            StatusCode::FORBIDDEN,
+                            format!("the proxy doesn't allow requests to {}", req.uri())))),
+                        HostPolicy::PassThrough => pass_through(&client, req),
+                        HostPolicy::Intercept =>
+                            forward::<T>(&client, &state, proxy_authorization.clone(), req),
+                    }
+                });
+                let mut connection = Http::new().serve_connection(stream, service);
+                let mut shutdown = shutdown;
+                future::poll_fn(move || {
+                    let shutting_down = match shutdown.as_mut().map(|shutdown| shutdown.poll()) {
+                        Some(Ok(Async::NotReady)) | None => false,
+                        Some(_) => true,
+                    };
+                    if shutting_down {
+                        shutdown = None;
+                        connection.graceful_shutdown();
+                    }
+                    connection.poll()
+                })
+                .map_err(|e| eprintln!("tunneled connection error: {}", e))
+            });
+        hyper::rt::spawn(tunnel);
+        Box::new(future::ok(Response::new(Body::empty())))
+    }
+}
+
+/// Connects to the host the client asked for, and once that worked answers the `CONNECT` and
+/// copies bytes between the two, so neither side can tell the proxy is there.
+fn tunnel(connector: &egress::Connector, req: Request<Body>) -> ResponseFuture {
+    let authority = match req.uri().authority_part() {
+        Some(authority) => authority.clone(),
+        None => return Box::new(future::ok(error_response(StatusCode::BAD_REQUEST,
+            format!("CONNECT requires a host, got {}", req.uri())))),
+    };
+    let server = connector.connect_tcp(authority.host(), authority.port_u16().unwrap_or(443));
+    Box::new(server.then(move |server| -> Result<Response<Body>, hyper::Error> {
+        let server = match server {
+            Ok(server) => server,
+            Err(e) => return Ok(error_response(StatusCode::BAD_GATEWAY,
+                format!("can't connect to {}: {}", authority, e))),
+        };
+        let tunnel = req.into_body().on_upgrade()
+            .map_err(|e| eprintln!("upgrade error: {}", e))
+            .and_then(move |upgraded: Upgraded| {
+                let (client_read, client_write) = upgraded.split();
+                let (server_read, server_write) = server.split();
+                let upstream = copy(client_read, server_write)
+                    .and_then(|(_, _, server_write)| shutdown(server_write));
+                let downstream = copy(server_read, client_write)
+                    .and_then(|(_, _, client_write)| shutdown(client_write));
+                upstream.join(downstream)
+                    .map(|_| ())
+                    .map_err(|e| eprintln!("tunnel error: {}", e))
+            });
+        hyper::rt::spawn(tunnel);
+        Ok(Response::new(Body::empty()))
+    }))
+}
+
+/// Sends a plain http request on as it is, except for the proxy's own headers.
+fn pass_through(client: &HttpsClient, mut req: Request<Body>) -> ResponseFuture {
+    req.headers_mut().remove(PROXY_AUTHORIZATION);
+    req.headers_mut().remove(XPROXYPROFILE);
+    Box::new(client.request(req)
+        .or_else(|e| Ok(error_response(StatusCode::BAD_GATEWAY,
+            format!("error forwarding request: {}", e)))))
+}
+
+/// Runs a single request through the `Mitm` hooks and sends it to its destination.
+/// `connect_authorization` is the `Proxy-Authorization` of the tunnel the request came through.
+fn forward<T: Mitm + Send + Sync + 'static>(
+    client: &HttpsClient,
+    state: &Arc<T::State>,
+    connect_authorization: Option<HeaderValue>,
+    mut req: Request<Body>) -> ResponseFuture {
+    let client = client.clone();
+    let proxy_authorization = req.headers_mut().remove(PROXY_AUTHORIZATION)
+        .or(connect_authorization);
+    let mitm = Arc::new(T::new(req.uri().clone(), proxy_authorization, state.clone()));
+    let request_mitm = mitm.clone();
+    let end_mitm = mitm.clone();
+    let response = mitm.request_headers(req)
+        .and_then(move |req| {
+            let req = match req {
+                Ok(req) => req,
+                Err(res) => return future::Either::B(future::ok(res)),
+            };
+            let (mut parts, body) = req.into_parts();
+            parts.headers.remove(XPROXYPROFILE);
+            let end = future::lazy(move || Ok(end_mitm.request_body_end()))
+                .into_stream()
+                .filter_map(|chunk| chunk);
+            let body = Body::wrap_stream(body
+                .map(move |chunk| request_mitm.request_body_chunk(chunk))
+                .chain(end)
+                .filter(|chunk| !chunk.is_empty()));
+            let response = client.request(Request::from_parts(parts, body))
+                .map(move |res| {
+                    let (parts, body) = mitm.response_headers(res).into_parts();
+                    let body = Body::wrap_stream(
+                        body.map(move |chunk| mitm.response_body_chunk(chunk)));
+                    Response::from_parts(parts, body)
+                });
+            future::Either::A(response)
+        })
+        .or_else(|e| Ok(error_response(StatusCode::BAD_GATEWAY,
+            format!("error forwarding request: {}", e))));
+    Box::new(response)
+}
+
+/// Requests inside a `CONNECT` tunnel only have a path, so rebuild the full uri from the host the
+/// tunnel was opened to.
+fn with_absolute_uri(mut req: Request<Body>, authority: &Authority) -> Request<Body> {
+    let mut parts = req.uri().clone().into_parts();
+    parts.scheme = Some(Scheme::HTTPS);
+    parts.authority = Some(authority.clone());
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some(PathAndQuery::from_static("/"));
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+/// A response from the proxy itself, with `message` as the body and in the `x-proxy-error` header.
+pub fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    eprintln!("{}", message);
+    // Header values can't have newlines and such, the body always has the whole message.
+    let header = HeaderValue::from_str(&message)
+        .unwrap_or_else(|_| HeaderValue::from_static("see response body"));
+    let mut res = Response::new(Body::from(message));
+    *res.status_mut() = status;
+    res.headers_mut().insert(XPROXYERROR, header);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Future};
+    use futures::Stream;
+    use hyper::service::service_fn_ok;
+    use hyper::{Body, Client, Request, Response, Server};
+
+    #[test]
+    fn test_pass_through_strips_proxy_headers() {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(|| service_fn_ok(|req: Request<Body>| {
+                let mut names: Vec<&str> = req.headers().keys().map(|name| name.as_str())
+                    .filter(|name| name.starts_with("x-") || name.starts_with("proxy-"))
+                    .collect();
+                names.sort();
+                Response::new(Body::from(names.join(",")))
+            }));
+        let addr = server.local_addr();
+        let client = Client::builder().build(super::egress::https_connector(1).unwrap());
+        let req = Request::get(format!("http://{}/", addr))
+            .header(super::PROXY_AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+            .header(super::XPROXYPROFILE, "alice:secret")
+            .header("x-other", "kept")
+            .body(Body::empty())
+            .unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let response = future::lazy(move || {
+            hyper::rt::spawn(server.map_err(|e| panic!("server error: {}", e)));
+            super::pass_through(&client, req)
+        });
+        let body = runtime.block_on(response.and_then(|res| res.into_body().concat2())).unwrap();
+        assert_eq!(&body[..], b"x-other");
+    }
+}
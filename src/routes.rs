@@ -0,0 +1,231 @@
+//! Routes for using the proxy as a plain HTTP server, for clients that can't be pointed at an
+//! `https_proxy` or made to trust its certificates (browsers, Grafana datasources, OpenAPI
+//! generators and so on).
+//!
+//! `/<service>/<region>/<path>` goes to `https://<service>.<region>.amazonaws.com/<path>`, and a
+//! `Route` named `<name>` sends `/<name>/<path>` to its base url followed by `<path>` instead.
+//!
+//! Separately, `Upstreams` send requests for AWS hosts somewhere else, like LocalStack or MinIO,
+//! after they have been signed for the AWS host.
+
+use aws_signature_proxy::aws_signature_builder::endpoints::{dns_suffix, matches_host};
+use http::uri::{Authority, Scheme, Uri};
+use hyper::header::{HeaderValue, HOST};
+use hyper::{Body, Request};
+
+use std::str::FromStr;
+
+/// Parsed from `NAME=BASE_URL`, e.g. `logs=https://search-logs.us-west-2.es.amazonaws.com`.
+#[derive(Debug, Clone)]
+pub struct Route {
+    name: String,
+    base: Uri,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.find('=').ok_or_else(|| format!("expected NAME=BASE_URL, got {:?}", s))?;
+        let name = s[..i].trim_matches('/');
+        let base = s[i + 1..].parse::<Uri>().map_err(|e| format!("bad url in {:?}: {}", s, e))?;
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("route names can't be empty or contain \"/\", got {:?}", s));
+        }
+        if base.scheme_part().is_none() || base.authority_part().is_none() {
+            return Err(format!("route urls need a scheme and a host, got {:?}", s));
+        }
+        Ok(Route { name: name.to_string(), base })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+    /// Whether `/<service>/<region>/` paths are routed to AWS.
+    by_service: bool,
+}
+
+impl Routes {
+    pub fn new(routes: Vec<Route>, by_service: bool) -> Self {
+        Routes { routes, by_service }
+    }
+
+    /// Whether requests to the proxy itself are routed at all.
+    pub fn is_enabled(&self) -> bool {
+        self.by_service || !self.routes.is_empty()
+    }
+
+    /// Returns the url `uri` (which only has a path) routes to, keeping the query as it is.
+    pub fn resolve(&self, uri: &Uri) -> Option<Uri> {
+        let path = uri.path().trim_start_matches('/');
+        let (name, rest) = split_segment(path);
+        let (base, rest) = match self.routes.iter().find(|route| route.name == name) {
+            Some(route) => (route.base.clone(), rest),
+            None if self.by_service => {
+                let (region, rest) = split_segment(rest);
+                // Anything else could change which host the url points at, e.g. with an `@`.
+                if !is_label(name) || !is_label(region) {
+                    return None;
+                }
+                let host = format!("{}.{}.{}", name, region, dns_suffix(region));
+                let base = format!("https://{}", host).parse::<Uri>().ok()?;
+                if base.host() != Some(host.as_str()) {
+                    return None;
+                }
+                (base, rest)
+            },
+            None => return None,
+        };
+        let query = uri.query().map(|query| format!("?{}", query)).unwrap_or_default();
+        format!("{}://{}{}/{}{}", base.scheme_str()?, base.authority_part()?,
+            base.path().trim_end_matches('/'), rest, query).parse().ok()
+    }
+}
+
+/// Parsed from `HOST_PATTERN=URL`, e.g. `*.amazonaws.com=http://localhost:4566`.  Only the scheme
+/// and host of the url are used, since the path is part of the signature.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host_pattern: String,
+    scheme: Scheme,
+    authority: Authority,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.find('=').ok_or_else(|| format!("expected HOST_PATTERN=URL, got {:?}", s))?;
+        let url = s[i + 1..].parse::<Uri>().map_err(|e| format!("bad url in {:?}: {}", s, e))?;
+        let parts = url.into_parts();
+        match (&s[..i], parts.scheme, parts.authority) {
+            (host_pattern, Some(scheme), Some(authority)) if !host_pattern.is_empty() =>
+                Ok(Upstream { host_pattern: host_pattern.to_lowercase(), scheme, authority }),
+            _ => Err(format!("expected HOST_PATTERN=URL with a scheme and host, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+}
+
+impl Upstreams {
+    pub fn new(upstreams: Vec<Upstream>) -> Self {
+        Upstreams { upstreams }
+    }
+
+    /// Sends `req` to the first upstream whose pattern matches its host.  The original host stays
+    /// in the `Host` header, which is what it was signed with and what tells LocalStack which
+    /// service (or S3 bucket) the request is for.
+    pub fn redirect(&self, mut req: Request<Body>) -> Request<Body> {
+        let upstream = match req.uri().host() {
+            Some(host) => self.upstreams.iter()
+                .find(|upstream| matches_host(&upstream.host_pattern, host)),
+            None => None,
+        };
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => return req,
+        };
+        if !req.headers().contains_key(HOST) {
+            let host = req.uri().authority_part()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+            if let Some(host) = host {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = Some(upstream.scheme.clone());
+        parts.authority = Some(upstream.authority.clone());
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+        req
+    }
+}
+
+/// Whether `label` can be used as is in a host name.
+fn is_label(label: &str) -> bool {
+    !label.is_empty() && label.bytes()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-')
+}
+
+/// Splits `a/b/c` into `a` and `b/c`.
+fn split_segment(path: &str) -> (&str, &str) {
+    match path.find('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (path, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::uri::Uri;
+
+    fn resolve(routes: &super::Routes, uri: &str) -> Option<String> {
+        routes.resolve(&uri.parse::<Uri>().unwrap()).map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let routes = super::Routes::new(vec![
+            "_dashboards=https://search-logs.us-west-2.es.amazonaws.com/_dashboards/".parse()
+                .unwrap(),
+        ], true);
+        let cases = [
+            ("/es/us-west-2/_search?q=error", "https://es.us-west-2.amazonaws.com/_search?q=error"),
+            ("/dynamodb/cn-north-1/", "https://dynamodb.cn-north-1.amazonaws.com.cn/"),
+            ("/_dashboards/app/home",
+                "https://search-logs.us-west-2.es.amazonaws.com/_dashboards/app/home"),
+        ];
+        for (path, url) in cases.iter() {
+            assert_eq!(resolve(&routes, path), Some(url.to_string()));
+        }
+        assert_eq!(resolve(&routes, "/es"), None);
+        assert_eq!(resolve(&routes, "/"), None);
+        // Only plain labels, so the path can't pick another host.
+        assert_eq!(resolve(&routes, "/x@evil.com:1/us-east-1/"), None);
+        assert_eq!(resolve(&routes, "/es/us-east-1@evil.com/"), None);
+        assert_eq!(resolve(&routes, "/es.evil.com%23/us-east-1/"), None);
+        assert_eq!(resolve(&routes, "/es/us-east-1:443/"), None);
+        assert_eq!(resolve(&routes, "/ES/us-east-1/"), None);
+        let routes = super::Routes::new(vec![], false);
+        assert!(!routes.is_enabled());
+        assert_eq!(resolve(&routes, "/es/us-west-2/"), None);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let upstreams = super::Upstreams::new(vec![
+            "*.s3.amazonaws.com=http://localhost:9000".parse().unwrap(),
+            "*.amazonaws.com=http://localhost:4566".parse().unwrap(),
+        ]);
+        let redirect = |uri: &str| {
+            let req = upstreams.redirect(hyper::Request::get(uri).body(hyper::Body::empty())
+                .unwrap());
+            (req.uri().to_string(), req.headers()["host"].to_str().unwrap().to_string())
+        };
+        assert_eq!(redirect("https://bucket.s3.amazonaws.com/key?acl"),
+            (String::from("http://localhost:9000/key?acl"),
+                String::from("bucket.s3.amazonaws.com")));
+        assert_eq!(redirect("https://sqs.us-east-1.amazonaws.com/"),
+            (String::from("http://localhost:4566/"), String::from("sqs.us-east-1.amazonaws.com")));
+        let req = upstreams.redirect(hyper::Request::get("https://example.com/")
+            .body(hyper::Body::empty()).unwrap());
+        assert_eq!(req.uri(), "https://example.com/");
+        assert!("*.amazonaws.com".parse::<super::Upstream>().is_err());
+        assert!("*.amazonaws.com=localhost".parse::<super::Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert!("logs=https://search-logs.us-west-2.es.amazonaws.com".parse::<super::Route>()
+            .is_ok());
+        assert!("logs".parse::<super::Route>().is_err());
+        assert!("=https://example.com".parse::<super::Route>().is_err());
+        assert!("logs=/relative".parse::<super::Route>().is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Signals that control a running proxy: `SIGHUP` reloads its settings and credentials, and
+//! `SIGTERM` stops it once the requests it is busy with are done.  Where there are no such signals,
+//! Ctrl-C stops it the same way and there is no reloading.
+
+use futures::future::{self, Future, Shared};
+use futures::Stream;
+use tokio_signal::{IoFuture, IoStream};
+
+/// Resolves once the proxy should stop taking new connections, and never if the signals can't be
+/// listened for.
+pub type Shutdown = Shared<Box<dyn Future<Item = (), Error = ()> + Send>>;
+
+/// The signals are only listened for once this is polled, which has to be inside the runtime.
+pub fn shutdown() -> Shutdown {
+    let signals = future::lazy(shutdown_signals);
+    let shutdown: Box<dyn Future<Item = (), Error = ()> + Send> = Box::new(signals
+        .and_then(|signals| signals.into_future().map_err(|(e, _)| e))
+        .map(|_| ())
+        .or_else(|e| {
+            eprintln!("can't listen for signals to shut down on: {}", e);
+            future::empty()
+        }));
+    shutdown.shared()
+}
+
+/// Yields every time the proxy should reload.
+pub fn reloads() -> Box<dyn Stream<Item = (), Error = ()> + Send> {
+    Box::new(future::lazy(reload_signals)
+        .flatten_stream()
+        .map_err(|e| eprintln!("can't listen for signals to reload on: {}", e)))
+}
+
+#[cfg(unix)]
+fn shutdown_signals() -> IoFuture<IoStream<()>> {
+    unix_signal(tokio_signal::unix::SIGTERM)
+}
+
+#[cfg(not(unix))]
+fn shutdown_signals() -> IoFuture<IoStream<()>> {
+    tokio_signal::ctrl_c()
+}
+
+#[cfg(unix)]
+fn reload_signals() -> IoFuture<IoStream<()>> {
+    unix_signal(tokio_signal::unix::SIGHUP)
+}
+
+#[cfg(not(unix))]
+fn reload_signals() -> IoFuture<IoStream<()>> {
+    Box::new(future::ok(Box::new(futures::stream::empty()) as IoStream<()>))
+}
+
+#[cfg(unix)]
+fn unix_signal(signal: i32) -> IoFuture<IoStream<()>> {
+    Box::new(tokio_signal::unix::Signal::new(signal)
+        .map(|signals| Box::new(signals.map(|_| ())) as IoStream<()>))
+}